@@ -2,6 +2,37 @@ use reqwest::Client;
 use serde_json::{json, Value};
 use tracing::{info, warn};
 
+const REDACTED: &str = "[REDACTED]";
+
+/// Environment variables holding credentials; their values never go out in an email.
+const SECRET_ENV_VARS: &[&str] = &[
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "TIGRIS_ACCESS_KEY_ID",
+    "TIGRIS_SECRET_ACCESS_KEY",
+    "FLY_API_TOKEN",
+    "SPRITES_TOKEN",
+    "RESEND_API_KEY",
+    "REGISTRY_PASSWORD",
+    "LANE_NOTIFY_BEARER_TOKEN",
+    "LANE_ADMIN_TOKEN",
+    "LANELAYER_ANALYTICS_AUTH_TOKEN",
+];
+
+/// Like [`SECRET_ENV_VARS`], but each holds a comma-separated list of secrets.
+const SECRET_LIST_ENV_VARS: &[&str] = &["LANE_NOTIFY_HMAC_SECRETS"];
+
+/// `name=value` pairs whose name contains one of these get their value masked.
+const SECRET_PARAM_HINTS: &[&str] = &[
+    "token",
+    "secret",
+    "password",
+    "passwd",
+    "signature",
+    "credential",
+    "key",
+];
+
 fn redact_token(token: &str) -> String {
     let trimmed = token.trim();
     if trimmed.len() <= 8 {
@@ -243,6 +274,109 @@ pub async fn send_lane_push_success_email(
     send_resend_email(recipients, &subject, &html).await
 }
 
+/// `error` and `log_excerpt` are passed through [`redact_secrets`] before sending.
+pub async fn send_lane_push_failed_email(
+    recipients: &[String],
+    target_image: &str,
+    digest: &str,
+    stage: &str,
+    error: &str,
+    log_excerpt: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let log_html = match log_excerpt {
+        Some(log) if !log.trim().is_empty() => format!(
+            "<p><strong>Last lines of output:</strong></p><pre>{}</pre>",
+            escape_html(&redact_secrets(log))
+        ),
+        _ => String::new(),
+    };
+
+    let subject = format!("Lane push failed: {}", target_image);
+    let html = format!(
+        "<h2>Lane push failed</h2>\
+         <p><strong>Target Image:</strong> {}</p>\
+         <p><strong>Digest:</strong> {}</p>\
+         <p><strong>Failed stage:</strong> {}</p>\
+         <p><strong>Error:</strong></p>\
+         <pre>{}</pre>\
+         {}",
+        target_image,
+        digest,
+        stage,
+        escape_html(&redact_secrets(error)),
+        log_html
+    );
+
+    send_resend_email(recipients, &subject, &html).await
+}
+
+/// Mask credentials in text that is about to leave the server: values of
+/// [`SECRET_ENV_VARS`], URL query strings (presigned S3 URLs carry their signature
+/// there), `Bearer` tokens, and `name=value` pairs whose name looks secret.
+pub fn redact_secrets(text: &str) -> String {
+    redact_secrets_with(text, &secret_env_values())
+}
+
+/// Current values of [`SECRET_ENV_VARS`] and the entries of [`SECRET_LIST_ENV_VARS`].
+fn secret_env_values() -> Vec<String> {
+    let single = SECRET_ENV_VARS
+        .iter()
+        .filter_map(|var| std::env::var(var).ok());
+    let listed = SECRET_LIST_ENV_VARS
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .flat_map(|list| list.split(',').map(|s| s.to_string()).collect::<Vec<_>>());
+    single.chain(listed).collect()
+}
+
+/// [`redact_secrets`] with an explicit list of secret values.
+fn redact_secrets_with(text: &str, secrets: &[String]) -> String {
+    let mut out = text.to_string();
+    for value in secrets {
+        let value = value.trim();
+        // Very short values would mask unrelated text.
+        if value.len() >= 6 {
+            out = out.replace(value, REDACTED);
+        }
+    }
+    out.lines().map(redact_line).collect::<Vec<_>>().join("\n")
+}
+
+fn redact_line(line: &str) -> String {
+    let mut mask_next = false;
+    line.split(' ')
+        .map(|word| {
+            if mask_next && !word.is_empty() {
+                mask_next = false;
+                return REDACTED.to_string();
+            }
+            if word.eq_ignore_ascii_case("bearer") {
+                mask_next = true;
+                return word.to_string();
+            }
+            if word.contains("://") {
+                if let Some((base, _query)) = word.split_once('?') {
+                    return format!("{}?{}", base, REDACTED);
+                }
+            }
+            if let Some((name, _value)) = word.split_once('=') {
+                let lower = name.to_ascii_lowercase();
+                if SECRET_PARAM_HINTS.iter().any(|hint| lower.contains(hint)) {
+                    return format!("{}={}", name, REDACTED);
+                }
+            }
+            word.to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 async fn send_resend_email(
     recipients: &[String],
     subject: &str,
@@ -282,3 +416,42 @@ async fn send_resend_email(
     info!("✅ Resend accepted email request");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_urls_bearer_tokens_and_secret_params() {
+        let text = "GET https://fly.storage.tigris.dev/lane-exports/x?X-Amz-Signature=abc failed\n\
+                    Authorization: Bearer eyJhbGciOi\n\
+                    retrying with password=hunter2 user=lane";
+        assert_eq!(
+            redact_secrets(text),
+            "GET https://fly.storage.tigris.dev/lane-exports/x?[REDACTED] failed\n\
+             Authorization: Bearer [REDACTED]\n\
+             retrying with password=[REDACTED] user=lane"
+        );
+    }
+
+    #[test]
+    fn redacts_secret_values() {
+        let secrets = [
+            "admin-token-value",
+            " first-hmac-secret",
+            "second-hmac-secret ",
+            "short",
+        ]
+        .map(String::from);
+        let text = "saw admin-token-value, first-hmac-secret and second-hmac-secret (short)";
+        assert_eq!(
+            redact_secrets_with(text, &secrets),
+            "saw [REDACTED], [REDACTED] and [REDACTED] (short)"
+        );
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape_html("<b>a & b</b>"), "&lt;b&gt;a &amp; b&lt;/b&gt;");
+    }
+}
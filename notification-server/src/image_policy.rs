@@ -0,0 +1,501 @@
+//! Which source images (and lane profiles) the server is willing to pull and build.
+//!
+//! Configured through environment variables, checked in [`check_profile`],
+//! [`check_before_pull`] (from the registry API, before any `docker pull`) and
+//! [`check_uncompressed_size`] / [`check_platforms`] (after it):
+//! - `LANE_ALLOWED_PROFILES` (default `prod,dev`): comma-separated profiles that may be
+//!   passed to `lane build` / `lane export`.
+//! - `LANE_SOURCE_ALLOWLIST`: comma-separated entries, each a registry host (`ttl.sh`), a
+//!   repository (`ghcr.io/lanelayer/app`) or a repository prefix (`ghcr.io/lanelayer/*`).
+//!   Unset or empty allows every source.
+//! - `LANE_REQUIRED_PLATFORM` (default `linux/riscv64`): must be among the notification's
+//!   platforms.
+//! - `LANE_MAX_COMPRESSED_IMAGE_BYTES` (default 5 GiB): layers + config as stored in the
+//!   registry, for the required platform.
+//! - `LANE_MAX_UNCOMPRESSED_IMAGE_BYTES` (default 20 GiB): size of the pulled image.
+//!
+//! Setting either size cap to `0` disables it.
+//!
+//! If the source registry refuses to show us the manifest (a private registry only docker
+//! has credentials for), the pull goes ahead: the platform is then checked on the pulled
+//! image, and the compressed size cap is skipped.
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::registry::{self, ImageRef};
+
+const DEFAULT_ALLOWED_PROFILES: &str = "prod,dev";
+const DEFAULT_REQUIRED_PLATFORM: &str = "linux/riscv64";
+const DEFAULT_MAX_COMPRESSED_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+const DEFAULT_MAX_UNCOMPRESSED_BYTES: u64 = 20 * 1024 * 1024 * 1024;
+
+/// Why an image was refused. Returned to the caller as-is.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    /// Stable machine-readable reason, e.g. `registry_not_allowed`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Rejection {
+    fn new(code: &'static str, message: String) -> Self {
+        Self { code, message }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+fn size_cap(var: &str, default: u64) -> Option<u64> {
+    let cap = std::env::var(var)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(default);
+    (cap > 0).then_some(cap)
+}
+
+pub fn required_platform() -> String {
+    std::env::var("LANE_REQUIRED_PLATFORM")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| DEFAULT_REQUIRED_PLATFORM.to_string())
+}
+
+/// `os/arch`, ignoring any variant, so `linux/arm64/v8` matches `linux/arm64`.
+fn os_arch(platform: &str) -> String {
+    platform
+        .split('/')
+        .take(2)
+        .collect::<Vec<_>>()
+        .join("/")
+        .to_ascii_lowercase()
+}
+
+fn normalize_host(host: &str) -> &str {
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io",
+        other => other,
+    }
+}
+
+fn allowlist() -> Vec<String> {
+    std::env::var("LANE_SOURCE_ALLOWLIST")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn allowed_profiles() -> Vec<String> {
+    let raw = std::env::var("LANE_ALLOWED_PROFILES")
+        .ok()
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_ALLOWED_PROFILES.to_string());
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The profile ends up in lane's argv and in S3 keys, so it must be a plain name as well
+/// as allowlisted.
+pub fn check_profile(profile: &str) -> Result<(), Rejection> {
+    check_profile_against(profile, &allowed_profiles())
+}
+
+fn check_profile_against(profile: &str, allowed: &[String]) -> Result<(), Rejection> {
+    let plain = profile
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric())
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !plain {
+        return Err(Rejection::new(
+            "invalid_profile",
+            format!("profile {:?} is not a valid profile name", profile),
+        ));
+    }
+
+    if !allowed.iter().any(|p| p == profile) {
+        return Err(Rejection::new(
+            "profile_not_allowed",
+            format!(
+                "profile {} is not allowed (allowed: {})",
+                profile,
+                allowed.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn check_allowlist(image: &ImageRef) -> Result<(), Rejection> {
+    check_allowlist_against(image, &allowlist())
+}
+
+fn check_allowlist_against(image: &ImageRef, entries: &[String]) -> Result<(), Rejection> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let host = normalize_host(&image.host);
+    let mut host_listed = false;
+    for entry in entries {
+        let (entry_host, entry_repo) = match entry.split_once('/') {
+            Some((h, r)) => (normalize_host(h), Some(r)),
+            None => (normalize_host(entry), None),
+        };
+        if entry_host != host {
+            continue;
+        }
+        host_listed = true;
+        let allowed = match entry_repo {
+            None => true,
+            Some(pattern) => match pattern.strip_suffix("/*") {
+                Some(prefix) => image.repository.starts_with(&format!("{}/", prefix)),
+                None => image.repository == pattern,
+            },
+        };
+        if allowed {
+            return Ok(());
+        }
+    }
+
+    Err(if host_listed {
+        Rejection::new(
+            "repository_not_allowed",
+            format!(
+                "repository {}/{} is not in the source allowlist",
+                image.host, image.repository
+            ),
+        )
+    } else {
+        Rejection::new(
+            "registry_not_allowed",
+            format!("registry {} is not in the source allowlist", image.host),
+        )
+    })
+}
+
+fn platform_of(descriptor_platform: &Value) -> Option<String> {
+    let os = descriptor_platform.get("os")?.as_str()?;
+    let arch = descriptor_platform.get("architecture")?.as_str()?;
+    Some(
+        match descriptor_platform.get("variant").and_then(|v| v.as_str()) {
+            Some(variant) => format!("{}/{}/{}", os, arch, variant),
+            None => format!("{}/{}", os, arch),
+        },
+    )
+}
+
+fn manifest_size(manifest: &Value) -> u64 {
+    let config = manifest
+        .get("config")
+        .and_then(|c| c.get("size"))
+        .and_then(|s| s.as_u64())
+        .unwrap_or(0);
+    let layers: u64 = manifest
+        .get("layers")
+        .and_then(|l| l.as_array())
+        .map(|layers| {
+            layers
+                .iter()
+                .filter_map(|l| l.get("size").and_then(|s| s.as_u64()))
+                .sum()
+        })
+        .unwrap_or(0);
+    config + layers
+}
+
+/// Platforms listed in a multi-platform index (`None` for a single-platform manifest).
+/// Attestation manifests, listed as `unknown/unknown`, are left out.
+pub fn index_platforms(manifest: &Value) -> Option<Vec<String>> {
+    let entries = manifest.get("manifests")?.as_array()?;
+    Some(
+        entries
+            .iter()
+            .filter_map(|entry| entry.get("platform").and_then(platform_of))
+            .filter(|platform| !platform.starts_with("unknown/"))
+            .collect(),
+    )
+}
+
+/// The required platform must be among `platforms`.
+pub fn check_platforms(platforms: &[String]) -> Result<(), Rejection> {
+    let required = required_platform();
+    if !platforms.iter().any(|p| os_arch(p) == os_arch(&required)) {
+        return Err(Rejection::new(
+            "platform_missing",
+            format!(
+                "platforms {:?} do not include the required {}",
+                platforms, required
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// What the registry says about an image without pulling it.
+struct RemoteImage {
+    platforms: Vec<String>,
+    /// Compressed size of the variant for the required platform, if the image has one.
+    compressed_size: Option<u64>,
+}
+
+async fn inspect_remote(
+    image: &ImageRef,
+    required: &str,
+) -> Result<RemoteImage, Box<dyn std::error::Error + Send + Sync>> {
+    let top = registry::manifest(image, &image.reference).await?;
+
+    if let Some(platforms) = index_platforms(&top) {
+        // Multi-platform index: platforms are listed inline; size needs the one we build.
+        let wanted_digest = top
+            .get("manifests")
+            .and_then(|m| m.as_array())
+            .into_iter()
+            .flatten()
+            .find(|entry| {
+                entry
+                    .get("platform")
+                    .and_then(platform_of)
+                    .is_some_and(|p| os_arch(&p) == os_arch(required))
+            })
+            .and_then(|entry| entry.get("digest"))
+            .and_then(|d| d.as_str())
+            .map(String::from);
+        let compressed_size = match wanted_digest {
+            Some(digest) => Some(manifest_size(&registry::manifest(image, &digest).await?)),
+            None => None,
+        };
+        return Ok(RemoteImage {
+            platforms,
+            compressed_size,
+        });
+    }
+
+    // Single-platform manifest: the platform lives in the config blob.
+    let config_digest = top
+        .get("config")
+        .and_then(|c| c.get("digest"))
+        .and_then(|d| d.as_str())
+        .ok_or("manifest has no config digest")?;
+    let config = registry::blob_json(image, config_digest).await?;
+    let platforms: Vec<String> = platform_of(&config).into_iter().collect();
+    let compressed_size = platforms
+        .iter()
+        .any(|p| os_arch(p) == os_arch(required))
+        .then(|| manifest_size(&top));
+    Ok(RemoteImage {
+        platforms,
+        compressed_size,
+    })
+}
+
+/// Everything that can be checked before pulling `source_image` (`repo@digest`).
+///
+/// `platforms` is what the notification declared; when it is empty (registry events carry
+/// none) it is filled in from the registry so the platform rule still applies. If the
+/// registry refuses inspection for auth reasons, `platforms` stays empty and the caller
+/// checks the pulled image instead (see [`check_platforms`]).
+pub async fn check_before_pull(
+    source_image: &str,
+    platforms: &mut Vec<String>,
+) -> Result<(), Rejection> {
+    let image =
+        ImageRef::parse(source_image).map_err(|e| Rejection::new("invalid_image_reference", e))?;
+    check_allowlist(&image)?;
+
+    let required = required_platform();
+    let max_compressed = size_cap(
+        "LANE_MAX_COMPRESSED_IMAGE_BYTES",
+        DEFAULT_MAX_COMPRESSED_BYTES,
+    );
+
+    let remote = if max_compressed.is_some() || platforms.is_empty() {
+        match inspect_remote(&image, &required).await {
+            Ok(remote) => Some(remote),
+            Err(e) if registry::is_unauthorized(e.as_ref()) => {
+                warn!(
+                    "🔐 Registry refused inspection of {} ({}); checking after the pull",
+                    source_image, e
+                );
+                None
+            }
+            Err(e) => {
+                return Err(Rejection::new(
+                    "manifest_unavailable",
+                    format!("could not read the manifest of {}: {}", source_image, e),
+                ))
+            }
+        }
+    } else {
+        None
+    };
+
+    if platforms.is_empty() {
+        match remote {
+            Some(ref remote) => {
+                info!(
+                    "🧭 No platforms in notification; registry lists {:?}",
+                    remote.platforms
+                );
+                platforms.clone_from(&remote.platforms);
+            }
+            // Nothing to check yet; the pipeline checks the pulled image.
+            None => return Ok(()),
+        }
+    }
+    check_platforms(platforms)?;
+
+    if let (Some(cap), Some(remote)) = (max_compressed, remote) {
+        let size = remote.compressed_size.ok_or_else(|| {
+            Rejection::new(
+                "platform_missing",
+                format!("image has no {} variant in the registry", required),
+            )
+        })?;
+        if size > cap {
+            return Err(Rejection::new(
+                "image_too_large",
+                format!(
+                    "compressed size {} bytes exceeds the {} byte limit",
+                    size, cap
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the size of the pulled image (as reported by `docker image inspect`).
+pub fn check_uncompressed_size(size: u64) -> Result<(), Rejection> {
+    match size_cap(
+        "LANE_MAX_UNCOMPRESSED_IMAGE_BYTES",
+        DEFAULT_MAX_UNCOMPRESSED_BYTES,
+    ) {
+        Some(cap) if size > cap => Err(Rejection::new(
+            "image_too_large_uncompressed",
+            format!(
+                "uncompressed size {} bytes exceeds the {} byte limit",
+                size, cap
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| e.to_string()).collect()
+    }
+
+    fn image(reference: &str) -> ImageRef {
+        ImageRef::parse(reference).unwrap()
+    }
+
+    #[test]
+    fn profile_must_be_plain_and_allowed() {
+        let allowed = list(&["prod", "dev"]);
+        assert!(check_profile_against("prod", &allowed).is_ok());
+        assert_eq!(
+            check_profile_against("staging", &allowed).unwrap_err().code,
+            "profile_not_allowed"
+        );
+        for bad in ["", "-prod", "../prod", "prod dev", "prod;rm"] {
+            assert_eq!(
+                check_profile_against(bad, &allowed).unwrap_err().code,
+                "invalid_profile",
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn empty_allowlist_allows_everything() {
+        assert!(check_allowlist_against(&image("ttl.sh/anything:1h"), &[]).is_ok());
+    }
+
+    #[test]
+    fn allowlist_matches_hosts_repositories_and_prefixes() {
+        let entries = list(&["ttl.sh", "ghcr.io/lanelayer/*", "docker.io/library/alpine"]);
+        for allowed in [
+            "ttl.sh/some/app:1h",
+            "ghcr.io/lanelayer/app:latest",
+            "ghcr.io/lanelayer/team/app:1",
+            "alpine:3",
+            "index.docker.io/library/alpine:3",
+        ] {
+            assert!(
+                check_allowlist_against(&image(allowed), &entries).is_ok(),
+                "{}",
+                allowed
+            );
+        }
+        assert_eq!(
+            check_allowlist_against(&image("ghcr.io/other/app:1"), &entries)
+                .unwrap_err()
+                .code,
+            "repository_not_allowed"
+        );
+        assert_eq!(
+            check_allowlist_against(&image("ghcr.io/lanelayer-evil/app:1"), &entries)
+                .unwrap_err()
+                .code,
+            "repository_not_allowed"
+        );
+        assert_eq!(
+            check_allowlist_against(&image("quay.io/lanelayer/app:1"), &entries)
+                .unwrap_err()
+                .code,
+            "registry_not_allowed"
+        );
+    }
+
+    #[test]
+    fn index_platforms_skip_attestations() {
+        let index = serde_json::json!({
+            "manifests": [
+                {"digest": "sha256:a", "platform": {"os": "linux", "architecture": "riscv64"}},
+                {"digest": "sha256:b", "platform": {"os": "linux", "architecture": "arm64", "variant": "v8"}},
+                {"digest": "sha256:c", "platform": {"os": "unknown", "architecture": "unknown"}}
+            ]
+        });
+        assert_eq!(
+            index_platforms(&index),
+            Some(list(&["linux/riscv64", "linux/arm64/v8"]))
+        );
+        assert_eq!(index_platforms(&serde_json::json!({"config": {}})), None);
+    }
+
+    #[test]
+    fn os_arch_ignores_variant_and_case() {
+        assert_eq!(os_arch("linux/arm64/v8"), "linux/arm64");
+        assert_eq!(os_arch("Linux/RISCV64"), "linux/riscv64");
+    }
+
+    #[test]
+    fn manifest_size_adds_config_and_layers() {
+        let manifest = serde_json::json!({
+            "config": {"size": 100},
+            "layers": [{"size": 1000}, {"size": 24}]
+        });
+        assert_eq!(manifest_size(&manifest), 1124);
+    }
+}
@@ -0,0 +1,140 @@
+//! In-process fan-out of job progress for `GET /jobs/{id}/events`.
+//!
+//! Each running job gets a broadcast channel. The job store publishes stage transitions
+//! into it and the lane runners publish each line of `lane build`/`lane export` output.
+//! Nothing here is persisted: a subscriber that connects late gets the current job
+//! snapshot from the store and then only the events that happen after it subscribed.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast;
+
+use crate::jobs::{JobStage, JobStatus};
+
+/// Per-job buffer; a subscriber that falls further behind than this skips ahead.
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+impl LogStream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobEvent {
+    /// The job moved into a new stage.
+    Stage { stage: JobStage, status: JobStatus },
+    /// One line of output from a lane child process.
+    Log { stream: LogStream, line: String },
+    /// The sprite is up and serving RPC.
+    Deployed { lane_rpc_url: String },
+    /// The job reached a terminal status; no further events follow.
+    Finished {
+        status: JobStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl JobEvent {
+    /// SSE `event:` name for this event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobEvent::Stage { .. } => "stage",
+            JobEvent::Log { .. } => "log",
+            JobEvent::Deployed { .. } => "deployed",
+            JobEvent::Finished { .. } => "finished",
+        }
+    }
+}
+
+fn channels() -> &'static Mutex<HashMap<String, broadcast::Sender<JobEvent>>> {
+    static CELL: OnceLock<Mutex<HashMap<String, broadcast::Sender<JobEvent>>>> = OnceLock::new();
+    CELL.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Subscribe to events for a job, creating its channel if nobody has published yet.
+///
+/// Only call this for a job that exists; pair it with [`unsubscribe`] when the job turns
+/// out to be finished or unreadable, or its channel is never dropped.
+pub fn subscribe(job_id: &str) -> broadcast::Receiver<JobEvent> {
+    let mut map = channels().lock().unwrap_or_else(|e| e.into_inner());
+    map.entry(job_id.to_string())
+        .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+        .subscribe()
+}
+
+/// Publish an event to current subscribers. Having no subscribers is not an error.
+///
+/// A `Finished` event also drops the job's channel, which ends every open stream.
+pub fn publish(job_id: &str, event: JobEvent) {
+    let mut map = channels().lock().unwrap_or_else(|e| e.into_inner());
+    let finished = matches!(event, JobEvent::Finished { .. });
+    if let Some(tx) = map.get(job_id) {
+        let _ = tx.send(event);
+    }
+    if finished {
+        map.remove(job_id);
+    }
+}
+
+/// Give up a subscription without waiting for `Finished` (e.g. the job had already
+/// finished). The job's channel goes too once nobody else is subscribed to it.
+pub fn unsubscribe(job_id: &str, receiver: broadcast::Receiver<JobEvent>) {
+    drop(receiver);
+    let mut map = channels().lock().unwrap_or_else(|e| e.into_inner());
+    if map.get(job_id).is_some_and(|tx| tx.receiver_count() == 0) {
+        map.remove(job_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_channel(job_id: &str) -> bool {
+        channels()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(job_id)
+    }
+
+    #[test]
+    fn unsubscribing_the_last_receiver_drops_the_channel() {
+        let id = uuid::Uuid::new_v4().to_string();
+        let first = subscribe(&id);
+        let second = subscribe(&id);
+        unsubscribe(&id, first);
+        assert!(has_channel(&id));
+        unsubscribe(&id, second);
+        assert!(!has_channel(&id));
+    }
+
+    #[test]
+    fn finished_event_ends_the_stream() {
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut events = subscribe(&id);
+        publish(
+            &id,
+            JobEvent::Finished {
+                status: JobStatus::Succeeded,
+                error: None,
+            },
+        );
+        assert!(matches!(events.try_recv(), Ok(JobEvent::Finished { .. })));
+        assert!(events.try_recv().is_err());
+        assert!(!has_channel(&id));
+    }
+}
@@ -0,0 +1,927 @@
+//! Durable job store for notify-triggered lane builds.
+//!
+//! Every `POST /notify` that passes validation gets a row in a SQLite database on the
+//! `/data` volume (override with `LANE_JOB_DB_PATH`). The background pipeline advances the
+//! row's stage as it goes, so after a restart we still know which digests were built,
+//! exported and deployed, and can pick unfinished jobs back up where they stopped.
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use tracing::{info, warn};
+
+use crate::job_events::{self, JobEvent};
+
+const DEFAULT_DB_PATH: &str = "/data/lane-jobs.sqlite3";
+const CANCELLED_MESSAGE: &str = "cancelled via API";
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records how many have run,
+/// so only append to this list; never edit an entry that has shipped.
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE jobs (
+    id               TEXT PRIMARY KEY,
+    digest           TEXT NOT NULL,
+    source_image     TEXT NOT NULL,
+    target_image     TEXT NOT NULL,
+    profile          TEXT NOT NULL,
+    session          TEXT,
+    stage            TEXT NOT NULL,
+    status           TEXT NOT NULL,
+    error            TEXT,
+    created_at       TEXT NOT NULL,
+    updated_at       TEXT NOT NULL,
+    stage_started_at TEXT NOT NULL,
+    finished_at      TEXT
+);
+CREATE INDEX jobs_digest_idx ON jobs (digest);
+"#,
+    r#"
+ALTER TABLE jobs ADD COLUMN lane_rpc_url TEXT;
+ALTER TABLE jobs ADD COLUMN artifact_keys TEXT NOT NULL DEFAULT '[]';
+CREATE INDEX jobs_session_idx ON jobs (session);
+CREATE INDEX jobs_status_idx ON jobs (status);
+"#,
+    r#"
+ALTER TABLE jobs ADD COLUMN original_path TEXT NOT NULL DEFAULT '';
+ALTER TABLE jobs ADD COLUMN registry_path TEXT NOT NULL DEFAULT '';
+ALTER TABLE jobs ADD COLUMN platforms TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE jobs ADD COLUMN force INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE jobs ADD COLUMN recipients TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE jobs ADD COLUMN resume_count INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+ALTER TABLE jobs ADD COLUMN failed_artifacts TEXT NOT NULL DEFAULT '[]';
+"#,
+    r#"
+ALTER TABLE jobs ADD COLUMN sprite_retired_at TEXT;
+"#,
+    r#"
+ALTER TABLE jobs ADD COLUMN announced_at TEXT;
+UPDATE jobs SET announced_at = created_at WHERE stage NOT IN ('queued', 'pull');
+"#,
+    r#"
+ALTER TABLE jobs ADD COLUMN mirror_digest TEXT;
+"#,
+];
+
+static STORE: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+/// Pipeline stage a job is currently in (or last reached, for finished jobs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStage {
+    Queued,
+    Pull,
+    Mirror,
+    Build,
+    Export,
+    Upload,
+    SpriteDeploy,
+    Email,
+    Done,
+}
+
+impl JobStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStage::Queued => "queued",
+            JobStage::Pull => "pull",
+            JobStage::Mirror => "mirror",
+            JobStage::Build => "build",
+            JobStage::Export => "export",
+            JobStage::Upload => "upload",
+            JobStage::SpriteDeploy => "sprite_deploy",
+            JobStage::Email => "email",
+            JobStage::Done => "done",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "queued" => JobStage::Queued,
+            "pull" => JobStage::Pull,
+            "mirror" => JobStage::Mirror,
+            "build" => JobStage::Build,
+            "export" => JobStage::Export,
+            "upload" => JobStage::Upload,
+            "sprite_deploy" => JobStage::SpriteDeploy,
+            "email" => JobStage::Email,
+            "done" => JobStage::Done,
+            _ => return None,
+        })
+    }
+}
+
+/// Overall outcome of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::TimedOut => "timed_out",
+        }
+    }
+
+    /// Whether the job is over and will not change again.
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled | JobStatus::TimedOut
+        )
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "queued" => JobStatus::Queued,
+            "running" => JobStatus::Running,
+            "succeeded" => JobStatus::Succeeded,
+            "failed" => JobStatus::Failed,
+            "cancelled" => JobStatus::Cancelled,
+            "timed_out" => JobStatus::TimedOut,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRecord {
+    pub id: String,
+    pub digest: String,
+    pub source_image: String,
+    /// Empty until the notification has been validated (see [`set_validated`]).
+    pub target_image: String,
+    /// Manifest digest the mirror push produced. Differs from `digest` when the source was
+    /// a multi-platform index or an OCI manifest.
+    pub mirror_digest: Option<String>,
+    pub profile: String,
+    pub original_path: String,
+    pub registry_path: String,
+    pub platforms: Vec<String>,
+    /// Rebuild even if the export already exists in Tigris.
+    pub force: bool,
+    /// Where notification emails go; resolved once when the job is created.
+    #[serde(skip_serializing)]
+    pub recipients: Vec<String>,
+    pub stage: JobStage,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    /// S3 keys under `lane-exports` written by the upload stage.
+    pub artifact_keys: Vec<String>,
+    /// Export files the upload stage could not get into Tigris (missing or failed).
+    pub failed_artifacts: Vec<String>,
+    /// Public RPC URL, set once the sprite is deployed.
+    pub lane_rpc_url: Option<String>,
+    /// When the job's sprite was stopped or destroyed through the admin API; its
+    /// `lane_rpc_url` no longer answers and the job is not reused for new notifications.
+    pub sprite_retired_at: Option<DateTime<Utc>>,
+    /// When the "started" email went out; a resumed job that has it set does not send it again.
+    pub announced_at: Option<DateTime<Utc>>,
+    /// How many times the job was picked back up after a server restart.
+    pub resume_count: u32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub stage_started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Fields supplied by `notify_handler` when a job is first recorded.
+#[derive(Debug, Clone)]
+pub struct NewJob {
+    pub digest: String,
+    pub source_image: String,
+    pub target_image: String,
+    pub profile: String,
+    pub session: Option<String>,
+    pub original_path: String,
+    pub registry_path: String,
+    pub platforms: Vec<String>,
+    pub force: bool,
+}
+
+/// Optional filters for [`list_jobs`]; unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct JobFilter {
+    pub digest: Option<String>,
+    pub session: Option<String>,
+    pub status: Option<JobStatus>,
+    pub limit: usize,
+}
+
+fn db_path() -> String {
+    std::env::var("LANE_JOB_DB_PATH").unwrap_or_else(|_| DEFAULT_DB_PATH.to_string())
+}
+
+/// Open (or create) the job database and run pending migrations. Call once at startup.
+pub fn init() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = db_path();
+    if let Some(parent) = Path::new(&path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut conn = Connection::open(&path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    migrate(&mut conn)?;
+
+    STORE
+        .set(Mutex::new(conn))
+        .map_err(|_| "job store already initialized")?;
+    info!("🗄️ Job store ready at {}", path);
+    Ok(())
+}
+
+/// Run the [`MIGRATIONS`] the database has not seen yet.
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let applied: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        info!("🗄️ Applied job store migration {}", i + 1);
+    }
+    Ok(())
+}
+
+fn with_conn<T>(
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let store = STORE.get().ok_or("job store not initialized")?;
+    let conn = store.lock().map_err(|_| "job store mutex poisoned")?;
+    Ok(f(&conn)?)
+}
+
+fn parse_ts(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+fn row_to_job(row: &Row<'_>) -> rusqlite::Result<JobRecord> {
+    let stage: String = row.get("stage")?;
+    let status: String = row.get("status")?;
+    let created_at: String = row.get("created_at")?;
+    let updated_at: String = row.get("updated_at")?;
+    let stage_started_at: String = row.get("stage_started_at")?;
+    let finished_at: Option<String> = row.get("finished_at")?;
+    let sprite_retired_at: Option<String> = row.get("sprite_retired_at")?;
+    let announced_at: Option<String> = row.get("announced_at")?;
+    let artifact_keys: String = row.get("artifact_keys")?;
+    let failed_artifacts: String = row.get("failed_artifacts")?;
+    let platforms: String = row.get("platforms")?;
+    let recipients: String = row.get("recipients")?;
+
+    Ok(JobRecord {
+        id: row.get("id")?,
+        digest: row.get("digest")?,
+        source_image: row.get("source_image")?,
+        target_image: row.get("target_image")?,
+        mirror_digest: row.get("mirror_digest")?,
+        profile: row.get("profile")?,
+        original_path: row.get("original_path")?,
+        registry_path: row.get("registry_path")?,
+        platforms: serde_json::from_str(&platforms).unwrap_or_default(),
+        force: row.get("force")?,
+        recipients: serde_json::from_str(&recipients).unwrap_or_default(),
+        stage: JobStage::parse(&stage).unwrap_or(JobStage::Queued),
+        status: JobStatus::parse(&status).unwrap_or(JobStatus::Failed),
+        error: row.get("error")?,
+        artifact_keys: serde_json::from_str(&artifact_keys).unwrap_or_default(),
+        failed_artifacts: serde_json::from_str(&failed_artifacts).unwrap_or_default(),
+        lane_rpc_url: row.get("lane_rpc_url")?,
+        sprite_retired_at: sprite_retired_at.as_deref().map(parse_ts),
+        announced_at: announced_at.as_deref().map(parse_ts),
+        resume_count: row.get("resume_count")?,
+        created_at: parse_ts(&created_at),
+        updated_at: parse_ts(&updated_at),
+        stage_started_at: parse_ts(&stage_started_at),
+        finished_at: finished_at.as_deref().map(parse_ts),
+    })
+}
+
+/// Outcome of [`find_or_create_job`].
+#[derive(Debug)]
+pub enum JobClaim {
+    /// No reusable job existed, so a new one was recorded.
+    Created(JobRecord),
+    /// A queued, running or succeeded job (whose sprite is still up) already covers this
+    /// digest and profile.
+    Existing(JobRecord),
+}
+
+fn insert_job(conn: &Connection, new: &NewJob) -> rusqlite::Result<JobRecord> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now().to_rfc3339();
+    let platforms = serde_json::to_string(&new.platforms).unwrap_or_else(|_| "[]".to_string());
+
+    conn.execute(
+        "INSERT INTO jobs (id, digest, source_image, target_image, profile, session,
+                           original_path, registry_path, platforms, force,
+                           stage, status, created_at, updated_at, stage_started_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?13, ?13)",
+        params![
+            id,
+            new.digest,
+            new.source_image,
+            new.target_image,
+            new.profile,
+            new.session,
+            new.original_path,
+            new.registry_path,
+            platforms,
+            new.force,
+            JobStage::Pull.as_str(),
+            JobStatus::Running.as_str(),
+            now,
+        ],
+    )?;
+    conn.query_row("SELECT * FROM jobs WHERE id = ?1", [&id], row_to_job)
+}
+
+/// Record a new job unconditionally. It starts in the `pull` stage, since that is the
+/// first thing we do.
+pub fn create_job(new: NewJob) -> Result<JobRecord, Box<dyn std::error::Error + Send + Sync>> {
+    with_conn(|conn| insert_job(conn, &new))
+}
+
+/// Return the newest queued, running or succeeded job for the same digest and profile, or
+/// record a new one if there is none. Succeeded jobs whose sprite was retired don't count.
+/// Both happen under the store lock, so concurrent retries of the same push cannot start
+/// two builds.
+pub fn find_or_create_job(
+    new: NewJob,
+) -> Result<JobClaim, Box<dyn std::error::Error + Send + Sync>> {
+    with_conn(|conn| {
+        let existing = conn
+            .query_row(
+                "SELECT * FROM jobs
+                 WHERE digest = ?1 AND profile = ?2 AND status IN (?3, ?4, ?5)
+                   AND NOT (status = ?5 AND sprite_retired_at IS NOT NULL)
+                 ORDER BY created_at DESC
+                 LIMIT 1",
+                params![
+                    new.digest,
+                    new.profile,
+                    JobStatus::Queued.as_str(),
+                    JobStatus::Running.as_str(),
+                    JobStatus::Succeeded.as_str(),
+                ],
+                row_to_job,
+            )
+            .optional()?;
+
+        match existing {
+            Some(job) => Ok(JobClaim::Existing(job)),
+            None => insert_job(conn, &new).map(JobClaim::Created),
+        }
+    })
+}
+
+pub fn get_job(id: &str) -> Result<Option<JobRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    with_conn(|conn| {
+        conn.query_row("SELECT * FROM jobs WHERE id = ?1", [id], row_to_job)
+            .optional()
+    })
+}
+
+/// Most recent jobs first, narrowed by whichever filters are set.
+pub fn list_jobs(
+    filter: &JobFilter,
+) -> Result<Vec<JobRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let status = filter.status.map(JobStatus::as_str);
+    let limit = filter.limit as i64;
+
+    with_conn(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT * FROM jobs
+             WHERE (?1 IS NULL OR digest = ?1)
+               AND (?2 IS NULL OR session = ?2)
+               AND (?3 IS NULL OR status = ?3)
+             ORDER BY created_at DESC
+             LIMIT ?4",
+        )?;
+        let rows = stmt.query_map(
+            params![filter.digest, filter.session, status, limit],
+            row_to_job,
+        )?;
+        rows.collect()
+    })
+}
+
+/// Jobs a previous process left queued or running, oldest first.
+pub fn unfinished_jobs() -> Result<Vec<JobRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    with_conn(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT * FROM jobs
+             WHERE status IN (?1, ?2)
+             ORDER BY created_at ASC",
+        )?;
+        let rows = stmt.query_map(UNFINISHED.map(JobStatus::as_str), row_to_job)?;
+        rows.collect()
+    })
+}
+
+/// Count one more restart-resume of a job and return the new total.
+pub fn record_resume(id: &str) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+    let now = Utc::now().to_rfc3339();
+    with_conn(|conn| {
+        conn.query_row(
+            "UPDATE jobs SET resume_count = resume_count + 1, updated_at = ?1
+             WHERE id = ?2
+             RETURNING resume_count",
+            params![now, id],
+            |row| row.get(0),
+        )
+    })
+}
+
+/// Run an UPDATE bound as `?1 = now, ?2 = id, ?3.. = extra`; returns whether a row changed.
+fn update(id: &str, sql: &str, extra: &[&dyn rusqlite::ToSql]) -> bool {
+    let now = Utc::now().to_rfc3339();
+    let mut values: Vec<&dyn rusqlite::ToSql> = vec![&now, &id];
+    values.extend_from_slice(extra);
+
+    match with_conn(|conn| conn.execute(sql, values.as_slice())) {
+        Ok(changed) => changed > 0,
+        Err(e) => {
+            warn!("⚠️ Failed to update job {} in job store: {}", id, e);
+            false
+        }
+    }
+}
+
+/// Statuses of a job that has not finished yet.
+const UNFINISHED: [JobStatus; 2] = [JobStatus::Queued, JobStatus::Running];
+
+/// [`update`] with `SET {set}`, applied only while the job is unfinished.
+fn update_unfinished(id: &str, set: &str, extra: &[&dyn rusqlite::ToSql]) -> bool {
+    let [queued, running] = UNFINISHED.map(JobStatus::as_str);
+    let sql = format!(
+        "UPDATE jobs SET {} WHERE id = ?2 AND status IN (?{}, ?{})",
+        set,
+        extra.len() + 3,
+        extra.len() + 4
+    );
+    let mut values = extra.to_vec();
+    values.extend_from_slice(&[&queued, &running]);
+    update(id, &sql, &values)
+}
+
+// The mutators below are best-effort: a job store hiccup is logged but never aborts
+// the pipeline that is already doing the real work. Status changes only apply to jobs
+// that are still queued or running; a finished job's outcome is final.
+
+/// Move a job into `stage` and mark it running.
+pub fn set_stage(id: &str, stage: JobStage) {
+    info!("📋 Job {} -> {}", id, stage.as_str());
+    let changed = update_unfinished(
+        id,
+        "stage = ?3, status = ?4, updated_at = ?1, stage_started_at = ?1",
+        &[&stage.as_str(), &JobStatus::Running.as_str()],
+    );
+    if !changed {
+        return;
+    }
+    job_events::publish(
+        id,
+        JobEvent::Stage {
+            stage,
+            status: JobStatus::Running,
+        },
+    );
+}
+
+/// Mark a job as waiting for a free build slot.
+///
+/// Returns `false` if the job already finished (e.g. it was cancelled while being
+/// validated), in which case it must not be handed to the pipeline.
+pub fn mark_queued(id: &str) -> bool {
+    info!("📋 Job {} -> queued", id);
+    let changed = update_unfinished(
+        id,
+        "stage = ?3, status = ?4, updated_at = ?1, stage_started_at = ?1",
+        &[&JobStage::Queued.as_str(), &JobStatus::Queued.as_str()],
+    );
+    if !changed {
+        return false;
+    }
+    job_events::publish(
+        id,
+        JobEvent::Stage {
+            stage: JobStage::Queued,
+            status: JobStatus::Queued,
+        },
+    );
+    true
+}
+
+/// Mark a job as failed in its current stage.
+pub fn mark_failed(id: &str, error: &str) {
+    warn!("📋 Job {} failed: {}", id, error);
+    finish_with_error(id, JobStatus::Failed, error);
+}
+
+/// Mark a job as timed out in its current stage.
+pub fn mark_timed_out(id: &str, error: &str) {
+    warn!("📋 Job {} timed out: {}", id, error);
+    finish_with_error(id, JobStatus::TimedOut, error);
+}
+
+fn finish_with_error(id: &str, status: JobStatus, error: &str) {
+    let changed = update_unfinished(
+        id,
+        "status = ?3, error = ?4, updated_at = ?1, finished_at = ?1",
+        &[&status.as_str(), &error],
+    );
+    if !changed {
+        return;
+    }
+    job_events::publish(
+        id,
+        JobEvent::Finished {
+            status,
+            error: Some(error.to_string()),
+        },
+    );
+}
+
+/// Mark a queued or running job as cancelled.
+///
+/// Returns the updated record, or `None` if the job does not exist or already finished.
+pub fn mark_cancelled(
+    id: &str,
+) -> Result<Option<JobRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let now = Utc::now().to_rfc3339();
+    let [queued, running] = UNFINISHED.map(JobStatus::as_str);
+    let changed = with_conn(|conn| {
+        conn.execute(
+            "UPDATE jobs SET status = ?3, error = ?4, updated_at = ?1, finished_at = ?1
+             WHERE id = ?2 AND status IN (?5, ?6)",
+            params![
+                now,
+                id,
+                JobStatus::Cancelled.as_str(),
+                CANCELLED_MESSAGE,
+                queued,
+                running
+            ],
+        )
+    })?;
+    if changed == 0 {
+        return Ok(None);
+    }
+
+    warn!("📋 Job {} cancelled", id);
+    job_events::publish(
+        id,
+        JobEvent::Finished {
+            status: JobStatus::Cancelled,
+            error: Some(CANCELLED_MESSAGE.to_string()),
+        },
+    );
+    get_job(id)
+}
+
+/// Record who gets this job's notification emails.
+pub fn set_recipients(id: &str, recipients: &[String]) {
+    let recipients = serde_json::to_string(recipients).unwrap_or_else(|_| "[]".to_string());
+    let _ = update(
+        id,
+        "UPDATE jobs SET recipients = ?3, updated_at = ?1 WHERE id = ?2",
+        &[&recipients],
+    );
+}
+
+/// Record what validating the notification settled: the mirror image picked for the job
+/// and its platforms (looked up in the registry when the notification had none).
+pub fn set_validated(id: &str, target_image: &str, platforms: &[String]) {
+    let platforms = serde_json::to_string(platforms).unwrap_or_else(|_| "[]".to_string());
+    let _ = update(
+        id,
+        "UPDATE jobs SET target_image = ?3, platforms = ?4, updated_at = ?1 WHERE id = ?2",
+        &[&target_image, &platforms],
+    );
+}
+
+/// Record the manifest digest the mirror push produced for this job.
+pub fn set_mirror_digest(id: &str, mirror_digest: &str) {
+    let _ = update(
+        id,
+        "UPDATE jobs SET mirror_digest = ?3, updated_at = ?1 WHERE id = ?2",
+        &[&mirror_digest],
+    );
+}
+
+/// Manifest digests earlier jobs pushed when mirroring `digest`, so a mirror tag holding
+/// one of them is known to be ours.
+pub fn mirror_digests(
+    digest: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    with_conn(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT mirror_digest FROM jobs
+             WHERE digest = ?1 AND mirror_digest IS NOT NULL",
+        )?;
+        let rows = stmt.query_map([digest], |row| row.get(0))?;
+        rows.collect()
+    })
+}
+
+/// Record that the "started" email for this job was sent.
+pub fn mark_announced(id: &str) {
+    let _ = update(
+        id,
+        "UPDATE jobs SET announced_at = ?1, updated_at = ?1 WHERE id = ?2",
+        &[],
+    );
+}
+
+/// Record the S3 keys the upload stage wrote for this job.
+pub fn set_artifact_keys(id: &str, keys: &[String]) {
+    let keys = serde_json::to_string(keys).unwrap_or_else(|_| "[]".to_string());
+    let _ = update(
+        id,
+        "UPDATE jobs SET artifact_keys = ?3, updated_at = ?1 WHERE id = ?2",
+        &[&keys],
+    );
+}
+
+/// Record which export files the upload stage could not get into Tigris.
+pub fn set_failed_artifacts(id: &str, files: &[String]) {
+    let files = serde_json::to_string(files).unwrap_or_else(|_| "[]".to_string());
+    let _ = update(
+        id,
+        "UPDATE jobs SET failed_artifacts = ?3, updated_at = ?1 WHERE id = ?2",
+        &[&files],
+    );
+}
+
+/// Record the public RPC URL of the sprite deployed for this job.
+pub fn set_lane_rpc_url(id: &str, rpc_url: &str) {
+    let _ = update(
+        id,
+        "UPDATE jobs SET lane_rpc_url = ?3, updated_at = ?1 WHERE id = ?2",
+        &[&rpc_url],
+    );
+    job_events::publish(
+        id,
+        JobEvent::Deployed {
+            lane_rpc_url: rpc_url.to_string(),
+        },
+    );
+}
+
+/// Note that the sprite serving `digest` in `profile` was stopped or destroyed, so the jobs
+/// that deployed it are no longer reused (see [`find_or_create_job`]). Returns how many
+/// jobs were marked.
+pub fn retire_sprite_jobs(
+    digest: &str,
+    profile: &str,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    with_conn(|conn| {
+        conn.execute(
+            "UPDATE jobs SET sprite_retired_at = ?1, updated_at = ?1
+             WHERE digest = ?2 AND profile = ?3 AND status = ?4 AND sprite_retired_at IS NULL",
+            params![
+                Utc::now().to_rfc3339(),
+                digest,
+                profile,
+                JobStatus::Succeeded.as_str()
+            ],
+        )
+    })
+}
+
+/// Mark a job as finished successfully.
+pub fn mark_succeeded(id: &str) {
+    info!("📋 Job {} succeeded", id);
+    let changed = update_unfinished(
+        id,
+        "stage = ?3, status = ?4, updated_at = ?1, stage_started_at = ?1, finished_at = ?1",
+        &[&JobStage::Done.as_str(), &JobStatus::Succeeded.as_str()],
+    );
+    if !changed {
+        return;
+    }
+    job_events::publish(
+        id,
+        JobEvent::Finished {
+            status: JobStatus::Succeeded,
+            error: None,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Point the store at a migrated in-memory database. Tests share it, so each one works
+    /// on its own digests.
+    fn use_memory_store() {
+        STORE.get_or_init(|| {
+            let mut conn = Connection::open_in_memory().unwrap();
+            migrate(&mut conn).unwrap();
+            Mutex::new(conn)
+        });
+    }
+
+    fn new_job(digest: &str, profile: &str) -> NewJob {
+        NewJob {
+            digest: digest.to_string(),
+            source_image: format!("ttl.sh/app@{}", digest),
+            target_image: String::new(),
+            profile: profile.to_string(),
+            session: None,
+            original_path: "ttl.sh/app:1h".to_string(),
+            registry_path: "ttl.sh/app:1h".to_string(),
+            platforms: vec!["linux/amd64".to_string()],
+            force: false,
+        }
+    }
+
+    fn unique_digest() -> String {
+        format!("sha256:{}", uuid::Uuid::new_v4().simple())
+    }
+
+    #[test]
+    fn migrations_run_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn records_and_reads_back_a_job() {
+        use_memory_store();
+        let digest = unique_digest();
+        let job = create_job(new_job(&digest, "prod")).unwrap();
+        assert_eq!(job.stage, JobStage::Pull);
+        assert_eq!(job.status, JobStatus::Running);
+
+        set_stage(&job.id, JobStage::Build);
+        let read = get_job(&job.id).unwrap().unwrap();
+        assert_eq!(read.digest, digest);
+        assert_eq!(read.platforms, vec!["linux/amd64".to_string()]);
+        assert_eq!(read.stage, JobStage::Build);
+        assert_eq!(read.finished_at, None);
+
+        mark_succeeded(&job.id);
+        let done = get_job(&job.id).unwrap().unwrap();
+        assert_eq!(done.stage, JobStage::Done);
+        assert_eq!(done.status, JobStatus::Succeeded);
+        assert!(done.finished_at.is_some());
+
+        assert!(get_job("no-such-job").unwrap().is_none());
+    }
+
+    #[test]
+    fn lists_newest_first_with_filters() {
+        use_memory_store();
+        let digest = unique_digest();
+        let first = create_job(new_job(&digest, "prod")).unwrap();
+        let mut with_session = new_job(&digest, "staging");
+        with_session.session = Some("session-1".to_string());
+        let second = create_job(with_session).unwrap();
+        mark_failed(&first.id, "boom");
+
+        let filter = JobFilter {
+            digest: Some(digest.clone()),
+            limit: 10,
+            ..Default::default()
+        };
+        let ids: Vec<String> = list_jobs(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|j| j.id)
+            .collect();
+        assert_eq!(ids, vec![second.id.clone(), first.id.clone()]);
+
+        let by_status = JobFilter {
+            status: Some(JobStatus::Failed),
+            ..filter.clone()
+        };
+        let failed = list_jobs(&by_status).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].id, first.id);
+        assert_eq!(failed[0].error.as_deref(), Some("boom"));
+
+        let by_session = JobFilter {
+            session: Some("session-1".to_string()),
+            ..filter.clone()
+        };
+        assert_eq!(list_jobs(&by_session).unwrap().len(), 1);
+
+        let limited = JobFilter { limit: 1, ..filter };
+        assert_eq!(list_jobs(&limited).unwrap()[0].id, second.id);
+    }
+
+    #[test]
+    fn reuses_unfinished_and_succeeded_jobs_only() {
+        use_memory_store();
+        let digest = unique_digest();
+        let created = match find_or_create_job(new_job(&digest, "prod")).unwrap() {
+            JobClaim::Created(job) => job,
+            JobClaim::Existing(job) => panic!("reused {}", job.id),
+        };
+        match find_or_create_job(new_job(&digest, "prod")).unwrap() {
+            JobClaim::Existing(job) => assert_eq!(job.id, created.id),
+            JobClaim::Created(job) => panic!("created {}", job.id),
+        }
+
+        // Another profile is another build.
+        assert!(matches!(
+            find_or_create_job(new_job(&digest, "staging")).unwrap(),
+            JobClaim::Created(_)
+        ));
+
+        mark_succeeded(&created.id);
+        assert!(matches!(
+            find_or_create_job(new_job(&digest, "prod")).unwrap(),
+            JobClaim::Existing(job) if job.id == created.id
+        ));
+
+        // A failed job is retried with a new one.
+        let other = unique_digest();
+        let failed = create_job(new_job(&other, "prod")).unwrap();
+        mark_failed(&failed.id, "pull failed");
+        assert!(matches!(
+            find_or_create_job(new_job(&other, "prod")).unwrap(),
+            JobClaim::Created(job) if job.id != failed.id
+        ));
+    }
+
+    #[test]
+    fn retired_sprite_jobs_are_not_reused() {
+        use_memory_store();
+        let digest = unique_digest();
+        let job = create_job(new_job(&digest, "prod")).unwrap();
+        mark_succeeded(&job.id);
+
+        assert_eq!(retire_sprite_jobs(&digest, "prod").unwrap(), 1);
+        assert_eq!(retire_sprite_jobs(&digest, "prod").unwrap(), 0);
+        assert!(get_job(&job.id)
+            .unwrap()
+            .unwrap()
+            .sprite_retired_at
+            .is_some());
+        assert!(matches!(
+            find_or_create_job(new_job(&digest, "prod")).unwrap(),
+            JobClaim::Created(created) if created.id != job.id
+        ));
+    }
+
+    #[test]
+    fn failing_a_job_binds_status_and_error() {
+        use_memory_store();
+        let job = create_job(new_job(&unique_digest(), "prod")).unwrap();
+        set_stage(&job.id, JobStage::Build);
+        mark_failed(&job.id, "lane exited with 1");
+
+        let job = get_job(&job.id).unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.stage, JobStage::Build);
+        assert_eq!(job.error.as_deref(), Some("lane exited with 1"));
+        assert!(job.finished_at.is_some());
+    }
+
+    #[test]
+    fn cancelled_jobs_stay_cancelled() {
+        use_memory_store();
+        let job = create_job(new_job(&unique_digest(), "prod")).unwrap();
+        let cancelled = mark_cancelled(&job.id).unwrap().unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+
+        assert!(!mark_queued(&job.id));
+        set_stage(&job.id, JobStage::Pull);
+        mark_failed(&job.id, "pull failed");
+        mark_timed_out(&job.id, "pull timed out");
+        mark_succeeded(&job.id);
+        assert!(mark_cancelled(&job.id).unwrap().is_none());
+
+        let job = get_job(&job.id).unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert_eq!(job.error.as_deref(), Some(CANCELLED_MESSAGE));
+    }
+}
@@ -1,12 +1,23 @@
 mod email;
+mod image_policy;
+mod job_events;
+mod jobs;
+mod multipart;
+mod naming;
+mod registry;
+mod registry_events;
 mod sprite;
 mod tigris;
+mod webhook_auth;
+mod workspace;
 
 use axum::{
     extract::Extension,
     extract::Json,
+    extract::{Path, Query},
     http::{header, Request, StatusCode, Uri},
     middleware::{self, Next},
+    response::sse::{Event, KeepAlive, Sse},
     response::{IntoResponse, Response},
     routing::{get, post},
     Router,
@@ -14,12 +25,18 @@ use axum::{
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use std::sync::OnceLock;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use job_events::{JobEvent, LogStream};
 
 use tokio::process::Command as TokioCommand;
-use tokio::sync::Semaphore;
+use tokio::sync::{RwLock, RwLockReadGuard, Semaphore};
 use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
+use workspace::JobWorkspace;
 
 #[derive(Clone, Debug)]
 struct NotifyForwardAuthToken(Option<String>);
@@ -43,6 +60,10 @@ struct LaneNotification {
     /// Accepts `session` (preferred) and also tolerates legacy payloads using `session_id`.
     #[serde(default, alias = "session_id")]
     session: Option<String>,
+    /// Rebuild even if a queued, running or succeeded job already exists for this
+    /// digest and profile. Without it, retries return the existing job.
+    #[serde(default)]
+    force: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -59,16 +80,152 @@ struct NotificationResponse {
     timestamp: DateTime<Utc>,
     #[serde(skip_serializing_if = "Option::is_none")]
     lane_rpc_url: Option<String>,
+    /// Poll `GET /jobs/{job_id}` for progress and the RPC URL once deployed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<String>,
+    /// Why the source image policy refused the image (status `Rejected`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejection: Option<image_policy::Rejection>,
+}
+
+#[derive(Debug, Serialize)]
+struct RegistryEventsResponse {
+    /// Tagged manifest pushes handed to the pipeline.
+    accepted: usize,
+    /// Everything else in the envelope (pulls, blobs, untagged manifests, our own mirrors).
+    ignored: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct JobListQuery {
+    #[serde(default)]
+    digest: Option<String>,
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct JobListResponse {
+    jobs: Vec<jobs::JobRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpriteListQuery {
+    #[serde(default)]
+    digest: Option<String>,
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SpriteListResponse {
+    chain_id: String,
+    updated_at: String,
+    sprites: Vec<tigris::SpriteIndexRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpriteTargetQuery {
+    /// Only sprites built for this profile when the target is a digest.
+    #[serde(default)]
+    profile: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpriteHistoryQuery {
+    #[serde(default)]
+    sprite: Option<String>,
+    #[serde(default)]
+    digest: Option<String>,
+    #[serde(default)]
+    job_id: Option<String>,
+    #[serde(default)]
+    since: Option<String>,
+    #[serde(default)]
+    until: Option<String>,
+    #[serde(default)]
+    before: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct SpriteHistoryResponse {
+    events: Vec<tigris::SpriteHistoryEvent>,
+    /// `?before=` for the next, older page; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
-/// If `LANE_NOTIFY_BEARER_TOKEN` is set, require `Authorization: Bearer <token>` for `POST /notify`.
+#[derive(Debug, Serialize)]
+struct SpriteActionResponse {
+    sprites: Vec<tigris::SpriteIndexRecord>,
+}
+
+/// Auth for the destructive `/sprites` admin endpoints: `LANE_ADMIN_TOKEN`, sent as
+/// `x-lane-admin-token` or `Authorization: Bearer`. Unlike notify auth there is no open
+/// mode: without a configured token the endpoints answer `503`.
+async fn admin_auth_middleware(req: Request<axum::body::Body>, next: Next) -> Response {
+    let Some(expected) = std::env::var("LANE_ADMIN_TOKEN")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+    else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Admin API disabled (set LANE_ADMIN_TOKEN)",
+        )
+            .into_response();
+    };
+
+    let given = req
+        .headers()
+        .get("x-lane-admin-token")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            req.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().strip_prefix("Bearer "))
+        })
+        .map(str::trim)
+        .filter(|s| !s.is_empty());
+    match given {
+        None => (
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized (provide x-lane-admin-token or Authorization bearer token)",
+        )
+            .into_response(),
+        Some(token) if !webhook_auth::tokens_match(token, &expected) => {
+            warn!("🔒 Rejected admin request with a wrong token");
+            (StatusCode::FORBIDDEN, "Forbidden").into_response()
+        }
+        Some(_) => next.run(req).await,
+    }
+}
+
+/// Largest request body buffered for signature verification.
+const MAX_SIGNED_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Auth for `POST /notify`, the `/jobs` endpoints and the read-only `/sprites` endpoints.
 ///
-/// If unset/empty, auth is disabled (useful for local dev), and requests are allowed through.
+/// - If `LANE_NOTIFY_HMAC_SECRETS` is set, requests carrying `x-lane-signature` must be
+///   signed with one of those secrets (see [`webhook_auth`]).
+/// - If `LANE_NOTIFY_BEARER_TOKEN` is set, unsigned requests must present that token.
+///
+/// If neither is set, auth is disabled (useful for local dev), and requests are allowed through.
 async fn notify_auth_middleware(req: Request<axum::body::Body>, next: Next) -> Response {
     let expected = std::env::var("LANE_NOTIFY_BEARER_TOKEN")
         .ok()
         .map(|s| s.trim().to_string())
         .filter(|s| !s.is_empty());
+    let hmac_secrets = webhook_auth::secrets();
 
     // Capture the user-provided bearer token (if any) so we can reuse it for analytics lookup.
     let forwarded_token = req
@@ -81,12 +238,47 @@ async fn notify_auth_middleware(req: Request<axum::body::Body>, next: Next) -> R
         .filter(|s| !s.is_empty())
         .map(|s| s.to_string());
 
-    // Auth disabled (local/dev) when env var isn't set.
-    let Some(expected_token) = expected else {
+    // Auth disabled (local/dev) when neither mode is configured.
+    if expected.is_none() && hmac_secrets.is_empty() {
         let mut req = req;
         req.extensions_mut()
             .insert(NotifyForwardAuthToken(forwarded_token));
         return next.run(req).await;
+    }
+
+    if !hmac_secrets.is_empty() && req.headers().contains_key(webhook_auth::SIGNATURE_HEADER) {
+        // The signature covers the raw body, so read it here and hand the handler a copy.
+        let (parts, body) = req.into_parts();
+        let bytes = match axum::body::to_bytes(body, MAX_SIGNED_BODY_BYTES).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("⚠️ Could not read signed request body: {}", e);
+                return (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response();
+            }
+        };
+        if let Err(e) = webhook_auth::verify(
+            &parts.method,
+            &parts.uri,
+            &parts.headers,
+            &bytes,
+            &hmac_secrets,
+        ) {
+            warn!("🔒 Rejected signed request: {}", e);
+            return (StatusCode::UNAUTHORIZED, format!("Unauthorized ({})", e)).into_response();
+        }
+
+        let mut req = Request::from_parts(parts, axum::body::Body::from(bytes));
+        req.extensions_mut()
+            .insert(NotifyForwardAuthToken(forwarded_token));
+        return next.run(req).await;
+    }
+
+    let Some(expected_token) = expected else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized (sign the request with x-lane-signature, x-lane-timestamp and x-lane-nonce)",
+        )
+            .into_response();
     };
 
     // Preferred: allow a dedicated header for the server-side webhook secret so we can
@@ -101,10 +293,10 @@ async fn notify_auth_middleware(req: Request<axum::body::Body>, next: Next) -> R
     let mut authed = false;
 
     if let Some(t) = webhook_token_header {
-        authed = t == expected_token;
+        authed = webhook_auth::tokens_match(t, &expected_token);
     } else if let Some(ref t) = forwarded_token {
         // Backward compatible: allow Authorization bearer token to act as webhook secret.
-        authed = t == &expected_token;
+        authed = webhook_auth::tokens_match(t, &expected_token);
     }
 
     if !authed {
@@ -121,11 +313,44 @@ async fn notify_auth_middleware(req: Request<axum::body::Body>, next: Next) -> R
     next.run(req).await
 }
 
+/// Number of jobs allowed through the pipeline at once (`LANE_BUILD_CONCURRENCY`, default 1).
+fn build_concurrency() -> usize {
+    std::env::var("LANE_BUILD_CONCURRENCY")
+        .ok()
+        .and_then(|s| s.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(1)
+}
+
 fn build_job_semaphore() -> &'static Semaphore {
-    // Lane build/export uses global resources (`/root/lane-export-temp`, Docker prune, etc).
-    // Serializing background jobs avoids concurrency-induced interference.
+    // Each job works in its own workspace, so the only limit is what the machine can take
+    // (lane builds are CPU/disk heavy). Default to one at a time.
     static CELL: OnceLock<Semaphore> = OnceLock::new();
-    CELL.get_or_init(|| Semaphore::new(1))
+    CELL.get_or_init(|| Semaphore::new(build_concurrency()))
+}
+
+/// Held (shared) by every job from its pull until its export finishes. The global Docker
+/// prunes need it exclusively, so they never remove a pulled image or build cache out from
+/// under another job.
+fn docker_state_lock() -> &'static RwLock<()> {
+    static CELL: OnceLock<RwLock<()>> = OnceLock::new();
+    CELL.get_or_init(|| RwLock::new(()))
+}
+
+/// Enter the Docker phase (pull through export), pruning Docker first if no other job is
+/// in it.
+async fn enter_docker_build() -> RwLockReadGuard<'static, ()> {
+    match docker_state_lock().try_write() {
+        Ok(exclusive) => {
+            log_disk_space("before cleanup").await;
+            cleanup_docker().await;
+            exclusive.downgrade()
+        }
+        Err(_) => {
+            info!("⏭️ Other jobs are using Docker; skipping docker prune");
+            docker_state_lock().read().await
+        }
+    }
 }
 
 async fn docker_pull_image(
@@ -136,6 +361,7 @@ async fn docker_pull_image(
     info!("🐳 Pulling image: {}", image_with_digest);
     let pull_status = TokioCommand::new("docker")
         .args(["pull", image_with_digest])
+        .kill_on_drop(true)
         .output()
         .await?;
 
@@ -147,10 +373,90 @@ async fn docker_pull_image(
     Ok(())
 }
 
+/// Size in bytes of a local image, as `docker image inspect` reports it.
+async fn docker_image_size(image: &str) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let output = TokioCommand::new("docker")
+        .args(["image", "inspect", "--format", "{{.Size}}", image])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("docker image inspect {} failed: {}", image, stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().parse()?)
+}
+
+/// Platforms of a pulled image: those of its index if it has one (docker only pulled the
+/// host's variant), otherwise the local image's own `os/arch[/variant]`.
+async fn pulled_image_platforms(
+    image: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    // Uses docker's registry login, which may reach registries our own client can't.
+    let manifest = TokioCommand::new("docker")
+        .args(["manifest", "inspect", image])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if manifest.status.success() {
+        if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&manifest.stdout) {
+            if let Some(platforms) = image_policy::index_platforms(&json) {
+                return Ok(platforms);
+            }
+        }
+    }
+
+    let output = TokioCommand::new("docker")
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{.Os}}/{{.Architecture}}{{with .Variant}}/{{.}}{{end}}",
+            image,
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("docker image inspect {} failed: {}", image, stderr.trim()).into());
+    }
+    Ok(vec![String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .to_string()])
+}
+
+async fn remove_image(image: &str) {
+    match TokioCommand::new("docker")
+        .args(["rmi", image])
+        .output()
+        .await
+    {
+        Ok(out) if !out.status.success() => warn!(
+            "Failed to remove image {}: {}",
+            image,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to remove image {}: {}", image, e),
+    }
+}
+
+/// Manifest digest `docker push` reports (`latest: digest: sha256:... size: 1234`).
+fn pushed_digest(push_output: &str) -> Option<String> {
+    push_output.lines().rev().find_map(|line| {
+        let rest = &line[line.find("digest: ")? + "digest: ".len()..];
+        let digest = rest.split_whitespace().next()?;
+        naming::digest_hex(digest).map(|_| digest.to_string())
+    })
+}
+
+/// Tag the pulled image as `target_image_tag` and push it. Returns the manifest digest
+/// the push produced.
 async fn tag_and_push_to_registry(
     source_image_with_digest: &str,
     target_image_tag: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     wait_for_docker().await?;
     wait_for_registry_login().await;
 
@@ -161,6 +467,7 @@ async fn tag_and_push_to_registry(
 
     let tag_status = TokioCommand::new("docker")
         .args(["tag", source_image_with_digest, target_image_tag])
+        .kill_on_drop(true)
         .output()
         .await?;
     if !tag_status.status.success() {
@@ -174,6 +481,7 @@ async fn tag_and_push_to_registry(
 
     let push_status = TokioCommand::new("docker")
         .args(["push", target_image_tag])
+        .kill_on_drop(true)
         .output()
         .await?;
     if !push_status.status.success() {
@@ -181,6 +489,84 @@ async fn tag_and_push_to_registry(
         return Err(format!("docker push {} failed: {}", target_image_tag, stderr).into());
     }
 
+    let stdout = String::from_utf8_lossy(&push_status.stdout);
+    pushed_digest(&stdout).ok_or_else(|| {
+        format!(
+            "docker push {} did not report a manifest digest: {}",
+            target_image_tag,
+            stdout.trim()
+        )
+        .into()
+    })
+}
+
+/// The mirrored image does not have the manifest digest the notification announced.
+#[derive(Debug)]
+struct DigestMismatch {
+    image: String,
+    expected: String,
+    actual: String,
+}
+
+impl std::fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "integrity check failed: {} resolved to {}, but expected {}",
+            self.image, self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+/// Check that the image we just pushed is the one the user announced.
+///
+/// The mirror tag must resolve to what `docker push` reported. That must also be the
+/// notification's digest when the source is a single-platform docker v2 manifest; for an
+/// index or OCI manifest the daemon pushes its own single-platform manifest, so the digests
+/// legitimately differ (the pull by digest already verified the source content).
+async fn verify_mirrored_digest(
+    source_image: &str,
+    target_image: &str,
+    pushed_digest: &str,
+    expected_digest: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let actual = registry::manifest_digest(&registry::ImageRef::parse(target_image)?).await?;
+    if actual != pushed_digest {
+        let mismatch = DigestMismatch {
+            image: target_image.to_string(),
+            expected: pushed_digest.to_string(),
+            actual,
+        };
+        error!("❌ {}", mismatch);
+        return Err(mismatch.into());
+    }
+
+    if actual != expected_digest {
+        let source = registry::ImageRef::parse(source_image)?;
+        match registry::manifest(&source, expected_digest).await {
+            Ok(manifest) if registry::is_docker_v2_manifest(&manifest) => {
+                let mismatch = DigestMismatch {
+                    image: target_image.to_string(),
+                    expected: expected_digest.to_string(),
+                    actual,
+                };
+                error!("❌ {}", mismatch);
+                return Err(mismatch.into());
+            }
+            Ok(_) => info!(
+                "🔏 {} is an index or OCI manifest; mirrored {} as {}",
+                expected_digest, target_image, actual
+            ),
+            Err(e) => warn!(
+                "⚠️ Could not read source manifest {} (skipping digest equality check): {}",
+                expected_digest, e
+            ),
+        }
+        return Ok(());
+    }
+    info!("🔏 Mirrored {} matches digest {}", target_image, actual);
     Ok(())
 }
 
@@ -194,12 +580,203 @@ async fn health_handler() -> impl IntoResponse {
     (StatusCode::OK, Json(response))
 }
 
+/// How long `POST /notify` waits for validation and job setup before answering anyway
+/// (`LANE_NOTIFY_DEADLINE_SECS`, default 20).
+fn notify_deadline() -> Duration {
+    let secs = std::env::var("LANE_NOTIFY_DEADLINE_SECS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(20);
+    Duration::from_secs(secs)
+}
+
 #[axum::debug_handler]
 async fn notify_handler(
     Extension(forwarded): Extension<NotifyForwardAuthToken>,
     Json(notification): Json<LaneNotification>,
 ) -> impl IntoResponse {
     let timestamp = Utc::now();
+    let container = notification.original_path.clone();
+
+    let recorded = match record_notification(notification) {
+        Recorded::Job(recorded) => recorded,
+        Recorded::Answered(status, response) => return (status, response),
+    };
+    let job_id = recorded.job_id.clone();
+
+    // Validation talks to registries and the analytics API; if they are slow, the
+    // notification keeps being processed in the background rather than holding the CLI,
+    // which can follow the already recorded job.
+    let deadline = notify_deadline();
+    let processing = tokio::spawn(queue_notification(recorded, forwarded.0));
+    match tokio::time::timeout(deadline, processing).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
+            error!("❌ Notification processing task failed: {}", e);
+            jobs::mark_failed(&job_id, &format!("notification processing failed: {}", e));
+            let response = NotificationResponse {
+                message: "❌ Failed to process notification".to_string(),
+                container,
+                status: "Failed".to_string(),
+                timestamp,
+                lane_rpc_url: None,
+                job_id: Some(job_id),
+                rejection: None,
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(response))
+        }
+        Err(_) => {
+            warn!(
+                "⏳ Job {} not validated within {:?}; continuing in the background",
+                job_id, deadline
+            );
+            let response = NotificationResponse {
+                message: format!(
+                    "⏳ Notification accepted and still being validated; follow GET /jobs/{}",
+                    job_id
+                ),
+                container,
+                status: "Accepted".to_string(),
+                timestamp,
+                lane_rpc_url: None,
+                job_id: Some(job_id),
+                rejection: None,
+            };
+            (StatusCode::ACCEPTED, Json(response))
+        }
+    }
+}
+
+/// Profile used for builds triggered by a plain `docker push` (registry events carry none).
+const REGISTRY_EVENT_PROFILE: &str = "prod";
+
+/// Repositories the mirror step pushes to (see [`naming`]). Their push events come back
+/// through `/registry-events` and must not start another build.
+fn is_mirror_repository(repository: &str) -> bool {
+    naming::is_derived_name(repository)
+}
+
+/// Who holds a mirror tag that resolves to `existing`: us if it is the source `digest`
+/// itself or a digest an earlier mirror of it pushed (`our_mirrors`; a multi-platform
+/// index or OCI source is stored under a different digest).
+fn mirror_owner(existing: String, digest: &str, our_mirrors: &[String]) -> naming::Owner {
+    if existing == digest || our_mirrors.contains(&existing) {
+        naming::Owner::Same
+    } else {
+        naming::Owner::Other(existing)
+    }
+}
+
+/// Pick the mirror image for `digest` in our registry: `lane-<hex>:latest`, with the
+/// shortest prefix whose tag is free or already holds a mirror of this digest.
+async fn mirror_target_image(
+    registry_base: &str,
+    digest: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let our_mirrors = &jobs::mirror_digests(digest)?;
+    let repository = naming::choose(digest, "mirror image", |name| async move {
+        let image = registry::ImageRef::parse(&format!("{}/{}:latest", registry_base, name))?;
+        Ok(match registry::manifest_digest_if_exists(&image).await? {
+            None => naming::Owner::Free,
+            Some(existing) => mirror_owner(existing, digest, our_mirrors),
+        })
+    })
+    .await?;
+    Ok(format!("{}/{}:latest", registry_base, repository))
+}
+
+/// Native registry notifications: each tagged manifest push becomes a notification for
+/// the same pipeline `POST /notify` feeds.
+async fn registry_events_handler(body: axum::body::Bytes) -> Response {
+    let (pushes, mut ignored) = match registry_events::manifest_pushes(&body) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            warn!("⚠️ Invalid registry event envelope: {}", e);
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid registry event envelope: {}", e),
+            )
+                .into_response();
+        }
+    };
+
+    let registry_base = registry::own_registry_base();
+    let mut accepted = 0;
+    for push in pushes {
+        if is_mirror_repository(&push.repository) {
+            info!("⏭️ Ignoring push to mirror repository {}", push.repository);
+            ignored += 1;
+            continue;
+        }
+
+        let image = format!("{}/{}:{}", registry_base, push.repository, push.tag);
+        info!(
+            "📦 Registry push event {}: {} ({}, {})",
+            push.event_id, image, push.digest, push.media_type
+        );
+        let notification = LaneNotification {
+            notification_type: "registry_push".to_string(),
+            original_path: image.clone(),
+            registry_path: image,
+            timestamp: push.timestamp,
+            success: true,
+            profile: REGISTRY_EVENT_PROFILE.to_string(),
+            platforms: Vec::new(),
+            digest: Some(push.digest),
+            session: None,
+            force: false,
+        };
+        // The registry blocks its notification queue on our response and retries on
+        // timeout, so the pull and job setup run in the background.
+        tokio::spawn(async move {
+            let (status, Json(response)) = process_notification(notification, None).await;
+            info!(
+                "📦 Registry push processed ({}): {} {}",
+                status, response.status, response.message
+            );
+        });
+        accepted += 1;
+    }
+
+    (
+        StatusCode::ACCEPTED,
+        Json(RegistryEventsResponse { accepted, ignored }),
+    )
+        .into_response()
+}
+
+/// Validate a push notification, record its job and queue the pipeline (which pulls the
+/// image). Used by `POST /registry-events`; `POST /notify` runs the two halves itself.
+async fn process_notification(
+    notification: LaneNotification,
+    forwarded_token: Option<String>,
+) -> (StatusCode, Json<NotificationResponse>) {
+    match record_notification(notification) {
+        Recorded::Job(recorded) => queue_notification(recorded, forwarded_token).await,
+        Recorded::Answered(status, response) => (status, response),
+    }
+}
+
+/// Outcome of [`record_notification`].
+enum Recorded {
+    /// The job is recorded; validation against the registry is still to come.
+    Job(RecordedNotification),
+    /// The notification ends here with this response.
+    Answered(StatusCode, Json<NotificationResponse>),
+}
+
+/// A notification whose job is recorded but not yet validated against the registry.
+struct RecordedNotification {
+    notification: LaneNotification,
+    source_image: String,
+    job_id: String,
+}
+
+/// The checks that need no network, then the job record, so that every accepted push has
+/// a job id to follow from the start. Anything that ends the notification here (a failed
+/// push, a bad digest or profile, a job that already covers the digest) is answered here.
+fn record_notification(notification: LaneNotification) -> Recorded {
+    let timestamp = Utc::now();
 
     info!("📢 Lane Notification Received:");
     info!("   Type: {}", notification.notification_type);
@@ -225,12 +802,14 @@ async fn notify_handler(
             status: "Failed".to_string(),
             timestamp,
             lane_rpc_url: None,
+            job_id: None,
+            rejection: None,
         };
 
-        return (StatusCode::OK, Json(response));
+        return Recorded::Answered(StatusCode::OK, Json(response));
     }
 
-    let digest = match notification.digest.as_deref() {
+    let digest = match notification.digest.clone() {
         Some(d) => d,
         None => {
             warn!("⚠️ No digest provided in notification");
@@ -240,13 +819,15 @@ async fn notify_handler(
                 status: "Warning".to_string(),
                 timestamp,
                 lane_rpc_url: None,
+                job_id: None,
+                rejection: None,
             };
-            return (StatusCode::OK, Json(response));
+            return Recorded::Answered(StatusCode::OK, Json(response));
         }
     };
 
-    // Require a sha256 digest so we can derive a stable short ID for the registry image name.
-    if !digest.starts_with("sha256:") {
+    // Require a sha256 digest so we can derive stable names for the registry image and sprite.
+    if naming::digest_hex(&digest).is_none() {
         warn!("No valid sha256 digest in notification (got {})", digest);
         let response = NotificationResponse {
             message: "⚠️ Invalid digest format in notification (expected sha256:...)".to_string(),
@@ -254,174 +835,1300 @@ async fn notify_handler(
             status: "Warning".to_string(),
             timestamp,
             lane_rpc_url: None,
+            job_id: None,
+            rejection: None,
+        };
+        return Recorded::Answered(StatusCode::OK, Json(response));
+    }
+
+    // 1) Source image (where lane CLI pushed the image, e.g. ttl.sh/...)
+    // Strip only a trailing tag, so a registry port (localhost:5000/app:tag) survives.
+    let source_repo = match notification.original_path.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => notification.original_path.as_str(),
+    };
+    let source_image_with_digest = format!("{}@{}", source_repo, digest);
+
+    // 2) The profile is a local setting; reject unknown ones before recording anything.
+    if let Err(rejection) = image_policy::check_profile(&notification.profile) {
+        warn!(
+            "🚫 Rejected {} by source image policy: {}",
+            source_image_with_digest, rejection
+        );
+        let response = NotificationResponse {
+            message: format!("🚫 Image rejected by policy: {}", rejection.message),
+            container: notification.original_path,
+            status: "Rejected".to_string(),
+            timestamp,
+            lane_rpc_url: None,
+            job_id: None,
+            rejection: Some(rejection),
+        };
+        return Recorded::Answered(StatusCode::UNPROCESSABLE_ENTITY, Json(response));
+    }
+
+    // 3) Record the job before talking to any registry so every accepted push is tracked.
+    // Retries of a push we already handled (or are handling) get the existing job back.
+    // The mirror image is picked during validation (see `queue_notification`).
+    let new_job = jobs::NewJob {
+        digest: digest.clone(),
+        source_image: source_image_with_digest.clone(),
+        target_image: String::new(),
+        profile: notification.profile.clone(),
+        session: notification.session.clone(),
+        original_path: notification.original_path.clone(),
+        registry_path: notification.registry_path.clone(),
+        platforms: notification.platforms.clone(),
+        force: notification.force,
+    };
+    let claim = if notification.force {
+        info!("🔁 force=true; starting a new job even if one exists for this digest");
+        jobs::create_job(new_job).map(jobs::JobClaim::Created)
+    } else {
+        jobs::find_or_create_job(new_job)
+    };
+    let job = match claim {
+        Ok(jobs::JobClaim::Created(job)) => job,
+        Ok(jobs::JobClaim::Existing(job)) => {
+            info!(
+                "♻️ Digest {} (profile {}) already handled by job {} ({}); not rebuilding",
+                digest,
+                job.profile,
+                job.id,
+                job.status.as_str()
+            );
+            let response = NotificationResponse {
+                message: format!(
+                    "♻️ Digest already {} by job {}; pass force=true to rebuild",
+                    if job.status == jobs::JobStatus::Succeeded {
+                        "deployed"
+                    } else {
+                        "in progress"
+                    },
+                    job.id
+                ),
+                // Still empty while the existing job is being validated.
+                container: if job.target_image.is_empty() {
+                    notification.registry_path
+                } else {
+                    job.target_image
+                },
+                status: "Duplicate".to_string(),
+                timestamp,
+                lane_rpc_url: job.lane_rpc_url,
+                job_id: Some(job.id),
+                rejection: None,
+            };
+            return Recorded::Answered(StatusCode::OK, Json(response));
+        }
+        Err(e) => {
+            error!("❌ Failed to record job in job store: {}", e);
+            let response = NotificationResponse {
+                message: format!("❌ Failed to record job: {}", e),
+                container: notification.registry_path,
+                status: "Failed".to_string(),
+                timestamp,
+                lane_rpc_url: None,
+                job_id: None,
+                rejection: None,
+            };
+            return Recorded::Answered(StatusCode::INTERNAL_SERVER_ERROR, Json(response));
+        }
+    };
+    info!("📋 Recorded job {} for digest {}", job.id, digest);
+
+    Recorded::Job(RecordedNotification {
+        notification,
+        source_image: source_image_with_digest,
+        job_id: job.id,
+    })
+}
+
+/// The checks that talk to registries (source image policy, mirror image name), then
+/// recipients, then the pipeline. A rejection fails the recorded job.
+async fn queue_notification(
+    recorded: RecordedNotification,
+    forwarded_token: Option<String>,
+) -> (StatusCode, Json<NotificationResponse>) {
+    let timestamp = Utc::now();
+    let RecordedNotification {
+        mut notification,
+        source_image: source_image_with_digest,
+        job_id,
+    } = recorded;
+    let digest = notification.digest.clone().unwrap_or_default();
+
+    // 4) Enforce the source image policy before pulling anything.
+    if let Err(rejection) =
+        image_policy::check_before_pull(&source_image_with_digest, &mut notification.platforms)
+            .await
+    {
+        warn!(
+            "🚫 Rejected {} by source image policy: {}",
+            source_image_with_digest, rejection
+        );
+        jobs::mark_failed(&job_id, &rejection.to_string());
+        let response = NotificationResponse {
+            message: format!("🚫 Image rejected by policy: {}", rejection.message),
+            container: notification.original_path,
+            status: "Rejected".to_string(),
+            timestamp,
+            lane_rpc_url: None,
+            job_id: Some(job_id),
+            rejection: Some(rejection),
+        };
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(response));
+    }
+
+    // 5) Target image in our own registry, named after the digest (never another digest's tag).
+    let registry_base = registry::own_registry_base();
+    let target_image = match mirror_target_image(&registry_base, &digest).await {
+        Ok(image) => image,
+        Err(e) => {
+            error!(
+                "❌ Could not pick a mirror image name for {}: {}",
+                digest, e
+            );
+            jobs::mark_failed(
+                &job_id,
+                &format!("could not pick a mirror image name: {}", e),
+            );
+            let response = NotificationResponse {
+                message: format!("❌ Could not pick a mirror image name: {}", e),
+                container: notification.registry_path,
+                status: "Failed".to_string(),
+                timestamp,
+                lane_rpc_url: None,
+                job_id: Some(job_id),
+                rejection: None,
+            };
+            return (StatusCode::BAD_GATEWAY, Json(response));
+        }
+    };
+    jobs::set_validated(&job_id, &target_image, &notification.platforms);
+
+    // 6) Resolve recipients BEFORE we do any heavy Docker work.
+    let recipients = match email::resolve_recipients(
+        notification.session.as_deref(),
+        forwarded_token.as_deref(),
+    )
+    .await
+    {
+        Ok(list) => list,
+        Err(e) => {
+            warn!(
+                "⚠️ Failed to resolve recipients from analytics/fallback list: {}",
+                e
+            );
+            Vec::new()
+        }
+    };
+
+    if recipients.is_empty() {
+        info!("📭 No recipients resolved (no analytics email and no RESEND_TO_EMAILS fallback)");
+    } else {
+        info!(
+            "📬 Resolved {} recipient(s) for notifications",
+            recipients.len()
+        );
+        // Stored so a job resumed after a restart can still email the user.
+        jobs::set_recipients(&job_id, &recipients);
+    }
+
+    // 7) Hand the job to the background pipeline, which starts by pulling the image;
+    // pull failures are reported through the job and the failure email from there on.
+    if !jobs::mark_queued(&job_id) {
+        info!("🛑 Job {} was cancelled before it was queued", job_id);
+        let response = NotificationResponse {
+            message: format!("🛑 Job {} was cancelled before it was queued", job_id),
+            container: target_image,
+            status: "Cancelled".to_string(),
+            timestamp,
+            lane_rpc_url: None,
+            job_id: Some(job_id),
+            rejection: None,
         };
         return (StatusCode::OK, Json(response));
     }
+    spawn_pipeline(PipelineJob {
+        job_id: job_id.clone(),
+        recipients,
+        original_path: notification.original_path,
+        registry_path: notification.registry_path,
+        profile: notification.profile,
+        platforms: notification.platforms,
+        digest,
+        force: notification.force,
+        source_image: source_image_with_digest,
+        target_image: target_image.clone(),
+        start: PipelineStart::Pull,
+        announce: true,
+        lane_rpc_url: None,
+    });
+
+    let response = NotificationResponse {
+        message: "✅ Notification received; lane deployment queued".to_string(),
+        container: target_image,
+        status: "Queued".to_string(),
+        timestamp,
+        lane_rpc_url: None,
+        job_id: Some(job_id),
+        rejection: None,
+    };
+
+    (StatusCode::OK, Json(response))
+}
+
+async fn get_job_handler(Path(id): Path<String>) -> Response {
+    match jobs::get_job(&id) {
+        Ok(Some(job)) => (StatusCode::OK, Json(job)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, format!("Job not found: {}", id)).into_response(),
+        Err(e) => {
+            error!("❌ Failed to read job {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read job: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn list_jobs_handler(Query(query): Query<JobListQuery>) -> Response {
+    const DEFAULT_LIMIT: usize = 50;
+    const MAX_LIMIT: usize = 500;
+
+    let status = match query.status.as_deref() {
+        Some(s) => match jobs::JobStatus::parse(s) {
+            Some(status) => Some(status),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Unknown job status: {}", s),
+                )
+                    .into_response();
+            }
+        },
+        None => None,
+    };
+
+    let filter = jobs::JobFilter {
+        digest: query.digest,
+        session: query.session,
+        status,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+    };
+
+    match jobs::list_jobs(&filter) {
+        Ok(jobs) => (StatusCode::OK, Json(JobListResponse { jobs })).into_response(),
+        Err(e) => {
+            error!("❌ Failed to list jobs: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to list jobs: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Cancel a queued or running job.
+///
+/// A running job's lane process group and containers are killed, its build slot freed and
+/// the export directory removed. Finished jobs are left alone (409).
+async fn cancel_job_handler(Path(id): Path<String>) -> Response {
+    // Stop the pipeline before recording the outcome, so it cannot write anything after us.
+    if let Some(cancel) = running_jobs()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&id)
+    {
+        cancel.cancel();
+    }
+
+    match jobs::mark_cancelled(&id) {
+        Ok(Some(job)) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Ok(None) => match jobs::get_job(&id) {
+            Ok(Some(job)) => (
+                StatusCode::CONFLICT,
+                format!("Job {} already {}", id, job.status.as_str()),
+            )
+                .into_response(),
+            Ok(None) => (StatusCode::NOT_FOUND, format!("Job not found: {}", id)).into_response(),
+            Err(e) => {
+                error!("❌ Failed to read job {}: {}", id, e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to read job: {}", e),
+                )
+                    .into_response()
+            }
+        },
+        Err(e) => {
+            error!("❌ Failed to cancel job {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to cancel job: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// List this chain's sprites from the sprite index, optionally filtered by digest, profile
+/// or status.
+async fn list_sprites_handler(Query(query): Query<SpriteListQuery>) -> Response {
+    match tigris::sprite_index().await {
+        Ok(index) => {
+            let sprites = index
+                .sprites
+                .into_iter()
+                .filter(|r| query.digest.as_ref().is_none_or(|d| &r.digest == d))
+                .filter(|r| query.profile.as_ref().is_none_or(|p| &r.profile == p))
+                .filter(|r| query.status.as_ref().is_none_or(|s| &r.status == s))
+                .collect();
+            let response = SpriteListResponse {
+                chain_id: index.chain_id,
+                updated_at: index.updated_at,
+                sprites,
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        Err(e) => {
+            error!("❌ Failed to read sprite index: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read sprite index: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Sprite index changes, newest first. `until` with `limit=1` answers "what was this
+/// sprite serving at that time".
+async fn sprite_history_handler(Query(query): Query<SpriteHistoryQuery>) -> Response {
+    const DEFAULT_LIMIT: usize = 50;
+    const MAX_LIMIT: usize = 500;
+
+    let parse_time = |name: &str, value: Option<&str>| match value {
+        Some(v) => DateTime::parse_from_rfc3339(v)
+            .map(|t| Some(t.with_timezone(&Utc)))
+            .map_err(|_| format!("Invalid {} (expected RFC 3339): {}", name, v)),
+        None => Ok(None),
+    };
+    let (since, until) = match (
+        parse_time("since", query.since.as_deref()),
+        parse_time("until", query.until.as_deref()),
+    ) {
+        (Ok(since), Ok(until)) => (since, until),
+        (Err(e), _) | (_, Err(e)) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let filter = tigris::SpriteHistoryFilter {
+        sprite_name: query.sprite,
+        digest: query.digest,
+        job_id: query.job_id,
+        since,
+        until,
+        before: query.before,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+    };
+
+    match tigris::sprite_history(&filter).await {
+        Ok(page) => {
+            let response = SpriteHistoryResponse {
+                events: page.events,
+                next_cursor: page.next_cursor,
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        Err(e) => {
+            error!("❌ Failed to read sprite history: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read sprite history: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// What to do to a sprite through the admin API.
+#[derive(Debug, Clone, Copy)]
+enum SpriteAction {
+    Stop,
+    Destroy,
+}
+
+impl SpriteAction {
+    fn verb(self) -> &'static str {
+        match self {
+            SpriteAction::Stop => "stop",
+            SpriteAction::Destroy => "destroy",
+        }
+    }
+
+    fn status(self) -> &'static str {
+        match self {
+            SpriteAction::Stop => tigris::SPRITE_STOPPED,
+            SpriteAction::Destroy => tigris::SPRITE_DESTROYED,
+        }
+    }
+}
+
+async fn stop_sprite_handler(
+    Path(target): Path<String>,
+    Query(query): Query<SpriteTargetQuery>,
+) -> Response {
+    sprite_action(SpriteAction::Stop, &target, query.profile.as_deref()).await
+}
+
+async fn destroy_sprite_handler(
+    Path(target): Path<String>,
+    Query(query): Query<SpriteTargetQuery>,
+) -> Response {
+    sprite_action(SpriteAction::Destroy, &target, query.profile.as_deref()).await
+}
+
+/// Stop or destroy the sprites `target` names: one sprite by name, or every sprite in the
+/// index for a `sha256:` digest (narrowed to `profile` if given).
+///
+/// Each sprite's index record is updated once the Sprites API call succeeds. Destroyed
+/// sprites can't be stopped or destroyed again (409).
+async fn sprite_action(action: SpriteAction, target: &str, profile: Option<&str>) -> Response {
+    let index = match tigris::sprite_index().await {
+        Ok(index) => index,
+        Err(e) => {
+            error!("❌ Failed to read sprite index: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read sprite index: {}", e),
+            )
+                .into_response();
+        }
+    };
+
+    let by_digest = target.starts_with("sha256:");
+    let matched: Vec<tigris::SpriteIndexRecord> = index
+        .sprites
+        .into_iter()
+        .filter(|r| {
+            if by_digest {
+                r.digest == target && profile.is_none_or(|p| r.profile == p)
+            } else {
+                r.sprite_name == target
+            }
+        })
+        .collect();
+    if matched.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Sprite not found: {}", target),
+        )
+            .into_response();
+    }
+
+    let live: Vec<&tigris::SpriteIndexRecord> = matched
+        .iter()
+        .filter(|r| r.status != tigris::SPRITE_DESTROYED)
+        .collect();
+    if live.is_empty() {
+        return (
+            StatusCode::CONFLICT,
+            format!("Sprite {} already {}", target, tigris::SPRITE_DESTROYED),
+        )
+            .into_response();
+    }
+
+    let mut done = Vec::new();
+    let mut failures = Vec::new();
+    for record in live {
+        let result = match action {
+            SpriteAction::Stop => sprite::stop_sprite(&record.sprite_name).await,
+            SpriteAction::Destroy => sprite::destroy_sprite(&record.sprite_name).await,
+        };
+        match result {
+            Ok(()) => {
+                // New notifications for this digest must deploy again, not get the old job.
+                if let Err(e) = jobs::retire_sprite_jobs(&record.digest, &record.profile) {
+                    warn!(
+                        "⚠️ Failed to retire jobs of sprite {}: {}",
+                        record.sprite_name, e
+                    );
+                }
+                done.push(record.sprite_name.clone());
+            }
+            Err(e) => {
+                error!(
+                    "❌ Failed to {} sprite {}: {}",
+                    action.verb(),
+                    record.sprite_name,
+                    e
+                );
+                failures.push(format!("{}: {}", record.sprite_name, e));
+            }
+        }
+    }
+
+    // Record what did happen, even if some sprites failed.
+    let updated = if done.is_empty() {
+        Vec::new()
+    } else {
+        match tigris::set_sprite_status(&done, action.status(), None).await {
+            Ok(updated) => updated,
+            Err(e) => {
+                error!(
+                    "❌ Sprites {:?} were marked {} but the index update failed: {}",
+                    done,
+                    action.status(),
+                    e
+                );
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to update sprite index: {}", e),
+                )
+                    .into_response();
+            }
+        }
+    };
+
+    if !failures.is_empty() {
+        return (
+            StatusCode::BAD_GATEWAY,
+            format!("Sprites API failed for {}", failures.join("; ")),
+        )
+            .into_response();
+    }
+    info!("✅ Sprite(s) {:?} now {}", done, action.status());
+    (
+        StatusCode::OK,
+        Json(SpriteActionResponse { sprites: updated }),
+    )
+        .into_response()
+}
+
+/// Stream a job's progress as Server-Sent Events.
+///
+/// The first event (`job`) is the current job record. After that come `stage`, `log`,
+/// `deployed` and finally `finished`, after which the stream ends.
+async fn job_events_handler(Path(id): Path<String>) -> Response {
+    // Only jobs that exist get an event channel.
+    match jobs::get_job(&id) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return (StatusCode::NOT_FOUND, format!("Job not found: {}", id)).into_response();
+        }
+        Err(e) => {
+            error!("❌ Failed to read job {}: {}", id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read job: {}", e),
+            )
+                .into_response();
+        }
+    }
+
+    // Subscribe before reading the snapshot so no transition can slip in between.
+    let mut events = job_events::subscribe(&id);
+    let job = match jobs::get_job(&id) {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            job_events::unsubscribe(&id, events);
+            return (StatusCode::NOT_FOUND, format!("Job not found: {}", id)).into_response();
+        }
+        Err(e) => {
+            job_events::unsubscribe(&id, events);
+            error!("❌ Failed to read job {}: {}", id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read job: {}", e),
+            )
+                .into_response();
+        }
+    };
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(64);
+    tokio::spawn(async move {
+        let snapshot = Event::default()
+            .event("job")
+            .json_data(&job)
+            .unwrap_or_else(|_| Event::default().comment("job snapshot unavailable"));
+        if job.status.is_terminal() {
+            job_events::unsubscribe(&job.id, events);
+            let _ = tx.send(Ok(snapshot)).await;
+            return;
+        }
+        if tx.send(Ok(snapshot)).await.is_err() {
+            return;
+        }
+
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    let note = Event::default().comment(format!("skipped {} events", skipped));
+                    if tx.send(Ok(note)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+            };
+
+            let finished = matches!(event, JobEvent::Finished { .. });
+            let sse = Event::default()
+                .event(event.name())
+                .json_data(&event)
+                .unwrap_or_else(|_| Event::default().comment("unserializable event"));
+            if tx.send(Ok(sse)).await.is_err() || finished {
+                return;
+            }
+        }
+    });
+
+    Sse::new(tokio_stream::wrappers::ReceiverStream::new(rx))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Forward a child process pipe line by line to the log and the job's event stream.
+/// Resolves to everything the pipe produced once it closes.
+fn forward_child_output<R>(
+    job_id: &str,
+    label: &'static str,
+    stream: LogStream,
+    reader: R,
+) -> tokio::task::JoinHandle<String>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    use tokio::io::AsyncBufReadExt;
+
+    let job_id = job_id.to_string();
+    tokio::spawn(async move {
+        let mut reader = tokio::io::BufReader::new(reader);
+        let mut collected = String::new();
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\r', '\n']);
+            collected.push_str(line);
+            collected.push('\n');
+            info!("[{} {}] {}", label, stream.as_str(), line);
+            job_events::publish(
+                &job_id,
+                JobEvent::Log {
+                    stream,
+                    line: line.to_string(),
+                },
+            );
+        }
+        collected
+    })
+}
+
+/// Everything the background pipeline needs, moved out of the notify request.
+struct PipelineJob {
+    job_id: String,
+    recipients: Vec<String>,
+    original_path: String,
+    registry_path: String,
+    profile: String,
+    platforms: Vec<String>,
+    digest: String,
+    force: bool,
+    source_image: String,
+    target_image: String,
+    /// First step to run; everything before it already happened.
+    start: PipelineStart,
+    /// Send the "deployment started" email (not again for a job that already sent it).
+    announce: bool,
+    /// Already-deployed RPC URL, for a job resumed at [`PipelineStart::Email`].
+    lane_rpc_url: Option<String>,
+}
+
+/// Pipeline steps in order. A fresh notification starts at `Pull`; a job resumed after a
+/// restart starts at the step it was interrupted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PipelineStart {
+    Pull,
+    Mirror,
+    Build,
+    Upload,
+    SpriteDeploy,
+    Email,
+}
+
+/// Cancellation handles for jobs whose pipeline task is alive in this process.
+fn running_jobs() -> &'static Mutex<HashMap<String, CancellationToken>> {
+    static CELL: OnceLock<Mutex<HashMap<String, CancellationToken>>> = OnceLock::new();
+    CELL.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Run the pipeline for `job` in the background, cancellable via `POST /jobs/{id}/cancel`.
+fn spawn_pipeline(job: PipelineJob) {
+    let cancel = CancellationToken::new();
+    running_jobs()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(job.job_id.clone(), cancel.clone());
+
+    tokio::spawn(async move {
+        let job_id = job.job_id.clone();
+        let target_image = job.target_image.clone();
+        // Set once the job holds a build slot; until then it has touched nothing to clean up.
+        let workspace = Arc::new(OnceLock::new());
+
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => {
+                // The pipeline future (and with it any lane process group and the build
+                // permit) has been dropped by now; clean up what it left behind.
+                warn!("🛑 Job {} cancelled", job_id);
+                cleanup_aborted_job(&workspace, &target_image).await;
+            }
+            _ = run_pipeline(job, workspace.clone()) => {}
+        }
+
+        if let Some(workspace) = workspace.get() {
+            workspace.remove().await;
+        }
+
+        running_jobs()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&job_id);
+    });
+}
+
+/// How many restarts a job may be resumed across before it is given up on
+/// (`LANE_MAX_JOB_RESUMES`, default 3). Stops a job that crashes the server from looping.
+fn max_job_resumes() -> u32 {
+    std::env::var("LANE_MAX_JOB_RESUMES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(3)
+}
+
+/// Pick up jobs a previous process left queued or running, oldest first.
+async fn resume_unfinished_jobs() {
+    let unfinished = match jobs::unfinished_jobs() {
+        Ok(list) => list,
+        Err(e) => {
+            warn!("⚠️ Could not load unfinished jobs to resume: {}", e);
+            return;
+        }
+    };
+    if unfinished.is_empty() {
+        return;
+    }
+
+    info!(
+        "🔄 Resuming {} job(s) interrupted by a restart",
+        unfinished.len()
+    );
+    // Needed to clear out containers the interrupted jobs left behind.
+    if let Err(e) = wait_for_docker().await {
+        warn!("⚠️ {}; resuming jobs anyway", e);
+    }
+    for job in unfinished {
+        resume_job(job).await;
+    }
+}
+
+async fn resume_job(job: jobs::JobRecord) {
+    if job.target_image.is_empty() {
+        abandon_job(
+            &job,
+            "interrupted while the notification was being validated; push again",
+        )
+        .await;
+        return;
+    }
+    let resumes = match jobs::record_resume(&job.id) {
+        Ok(n) => n,
+        Err(e) => {
+            warn!("⚠️ Could not record resume of job {}: {}", job.id, e);
+            return;
+        }
+    };
+    if resumes > max_job_resumes() {
+        let reason = format!(
+            "interrupted by {} server restarts in the {} stage; not resuming again",
+            resumes,
+            job.stage.as_str()
+        );
+        abandon_job(&job, &reason).await;
+        return;
+    }
+
+    // The lane/docker processes died with the old server; their containers may not have.
+    let workspace = JobWorkspace::for_job(&job.id);
+    remove_job_containers(&workspace, &job.target_image).await;
+
+    let start = match job.stage {
+        jobs::JobStage::Pull | jobs::JobStage::Queued | jobs::JobStage::Mirror => {
+            PipelineStart::Pull
+        }
+        jobs::JobStage::Build | jobs::JobStage::Export => PipelineStart::Build,
+        jobs::JobStage::Upload => {
+            let squashfs = workspace.export_dir().join(tigris::squashfs_filename());
+            if tokio::fs::try_exists(&squashfs).await.unwrap_or(false) {
+                PipelineStart::Upload
+            } else {
+                PipelineStart::Build
+            }
+        }
+        jobs::JobStage::SpriteDeploy => PipelineStart::SpriteDeploy,
+        jobs::JobStage::Email | jobs::JobStage::Done => {
+            if job.lane_rpc_url.is_some() {
+                PipelineStart::Email
+            } else {
+                PipelineStart::SpriteDeploy
+            }
+        }
+    };
+    info!(
+        "🔄 Resuming job {} (digest {}) from {:?}; was in {}",
+        job.id,
+        job.digest,
+        start,
+        job.stage.as_str()
+    );
+
+    spawn_pipeline(PipelineJob {
+        job_id: job.id,
+        recipients: job.recipients,
+        original_path: job.original_path,
+        registry_path: job.registry_path,
+        profile: job.profile,
+        platforms: job.platforms,
+        digest: job.digest,
+        force: job.force,
+        source_image: job.source_image,
+        target_image: job.target_image,
+        start,
+        announce: job.announced_at.is_none(),
+        lane_rpc_url: job.lane_rpc_url,
+    });
+}
+
+/// Fail a job that cannot be resumed and let the user know.
+async fn abandon_job(job: &jobs::JobRecord, reason: &str) {
+    error!("❌ Giving up on job {}: {}", job.id, reason);
+    jobs::mark_failed(&job.id, reason);
+    JobWorkspace::for_job(&job.id).remove().await;
+
+    if job.recipients.is_empty() {
+        return;
+    }
+    if let Err(e) = email::send_lane_push_failed_email(
+        &job.recipients,
+        &job.target_image,
+        &job.digest,
+        job.stage.as_str(),
+        reason,
+        None,
+    )
+    .await
+    {
+        warn!("⚠️ Failed to send lane push failed email: {}", e);
+    }
+}
+
+/// A pipeline stage ran past its time limit.
+#[derive(Debug)]
+struct StageTimeout {
+    stage: jobs::JobStage,
+    after: Duration,
+}
+
+impl std::fmt::Display for StageTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} stage timed out after {}s",
+            self.stage.as_str(),
+            self.after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for StageTimeout {}
+
+/// Time limit for one pipeline stage, overridable with `LANE_<STAGE>_TIMEOUT_SECS`
+/// (e.g. `LANE_BUILD_TIMEOUT_SECS`, `LANE_SPRITE_DEPLOY_TIMEOUT_SECS`).
+fn stage_timeout(stage: jobs::JobStage) -> Duration {
+    let default_secs = match stage {
+        jobs::JobStage::Pull | jobs::JobStage::Mirror => 15 * 60,
+        jobs::JobStage::Build => 60 * 60,
+        jobs::JobStage::Export | jobs::JobStage::Upload => 30 * 60,
+        jobs::JobStage::SpriteDeploy => 15 * 60,
+        jobs::JobStage::Queued | jobs::JobStage::Email | jobs::JobStage::Done => 5 * 60,
+    };
+    let var = format!("LANE_{}_TIMEOUT_SECS", stage.as_str().to_uppercase());
+    let secs = std::env::var(&var)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(default_secs);
+    Duration::from_secs(secs)
+}
+
+/// Run `fut` under `stage`'s time limit. On expiry the future is dropped, which kills any
+/// child process it owns (`kill_on_drop` / [`ProcessGroupGuard`]), and a [`StageTimeout`]
+/// is returned.
+async fn with_stage_timeout<T>(
+    stage: jobs::JobStage,
+    fut: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let limit = stage_timeout(stage);
+    match tokio::time::timeout(limit, fut).await {
+        Ok(result) => result,
+        Err(_) => {
+            error!("⏱️ {} stage timed out after {:?}", stage.as_str(), limit);
+            Err(Box::new(StageTimeout {
+                stage,
+                after: limit,
+            }))
+        }
+    }
+}
+
+/// Remove containers a job cut off mid-stage (cancelled or timed out) may have left
+/// running. Its workspace is removed separately once the pipeline task ends.
+async fn cleanup_aborted_job(workspace: &OnceLock<JobWorkspace>, image: &str) {
+    // A job that never got a build slot has not started any containers.
+    let Some(workspace) = workspace.get() else {
+        return;
+    };
+    remove_job_containers(workspace, image).await;
+}
+
+/// Lines of each output stream quoted in a failure email.
+const FAILURE_EMAIL_LOG_LINES: usize = 40;
+
+/// Record a stage failure and email the user about it. Timeouts get their own outcome
+/// and, since the stage was cut off mid-flight, the same cleanup as a cancellation.
+async fn fail_job(
+    job: &PipelineJob,
+    workspace: &OnceLock<JobWorkspace>,
+    err: Box<dyn std::error::Error + Send + Sync>,
+) {
+    let stage = match jobs::get_job(&job.job_id) {
+        Ok(Some(record)) => record.stage.as_str(),
+        _ => "unknown",
+    };
 
-    // 1) Get the first 8 characters after "sha256:" (used to name our registry image).
-    let short = &digest["sha256:".len()..];
-    let short8: String = short.chars().take(8).collect();
+    // A failed lane command's message embeds its whole output; the job record and the
+    // email get the first line as the summary, and the email the tail of the output.
+    let (summary, log_excerpt) = match err.downcast_ref::<LaneCommandFailed>() {
+        Some(failed) => (
+            failed.summary(),
+            Some(failed.output_tail(FAILURE_EMAIL_LOG_LINES)),
+        ),
+        None => (err.to_string(), None),
+    };
+    // The job record is readable through the API, so it never holds credentials.
+    let recorded_error = email::redact_secrets(&summary);
+    if err.is::<StageTimeout>() {
+        cleanup_aborted_job(workspace, &job.target_image).await;
+        jobs::mark_timed_out(&job.job_id, &recorded_error);
+    } else {
+        jobs::mark_failed(&job.job_id, &recorded_error);
+    }
 
-    // 2) Source image (where lane CLI pushed the image, e.g. ttl.sh/...)
-    let source_image_with_digest = format!(
-        "{}@{}",
-        notification
-            .original_path
-            .split(':')
-            .next()
-            .unwrap_or(&notification.original_path),
-        digest
-    );
+    if job.recipients.is_empty() {
+        return;
+    }
+    if let Err(e) = email::send_lane_push_failed_email(
+        &job.recipients,
+        &job.target_image,
+        &job.digest,
+        stage,
+        &summary,
+        log_excerpt.as_deref(),
+    )
+    .await
+    {
+        warn!("⚠️ Failed to send lane push failed email: {}", e);
+    }
+}
 
-    // 3) Target image in our own registry, derived from digest: cli-backend-registry.fly.dev/lane-<short8>:latest
-    let registry_base = std::env::var("LANE_REGISTRY_BASE")
-        .unwrap_or_else(|_| "cli-backend-registry.fly.dev".to_string());
-    let target_image = format!("{}/lane-{}:latest", registry_base, short8);
+async fn run_pipeline(job: PipelineJob, workspace: Arc<OnceLock<JobWorkspace>>) {
+    let _permit = build_job_semaphore().acquire().await;
+    let job_workspace = JobWorkspace::for_job(&job.job_id);
+    if let Err(e) = job_workspace.prepare().await {
+        error!("❌ Failed to create job workspace: {}", e);
+        jobs::mark_failed(
+            &job.job_id,
+            &format!("failed to create job workspace: {}", e),
+        );
+        return;
+    }
+    let workspace_ref = workspace.get_or_init(|| job_workspace);
 
-    // 4) Resolve recipients BEFORE we do any heavy Docker work.
-    let recipients =
-        match email::resolve_recipients(notification.session.as_deref(), forwarded.0.as_deref())
-            .await
+    // Background step 1: tell user we're starting deployment (only if we resolved a recipient).
+    if job.recipients.is_empty() {
+        info!("📭 No recipient email resolved; skipping start/success emails");
+    } else if !job.announce {
+        // Resumed after the started email already went out.
+    } else {
+        match email::send_lane_push_started_email(
+            &job.recipients,
+            &job.original_path,
+            &job.registry_path,
+            Some(&job.digest),
+            &job.profile,
+            &job.platforms,
+        )
+        .await
         {
-            Ok(list) => list,
+            Ok(()) => jobs::mark_announced(&job.job_id),
+            Err(e) => warn!("⚠️ Failed to send lane push started email: {}", e),
+        }
+    }
+
+    // Background step 2: pull the source image (cheap for a resumed job if the image
+    // survived the restart) and check its uncompressed size, only known once it's here.
+    // The Docker guard is held from here until the export finishes, so no other job's
+    // prune removes the pulled image or mirror tag before lane builds from them.
+    let docker_state = if job.start <= PipelineStart::Build {
+        Some(enter_docker_build().await)
+    } else {
+        None
+    };
+    if job.start <= PipelineStart::Pull {
+        jobs::set_stage(&job.job_id, jobs::JobStage::Pull);
+        // The post-pull inspections share the pull's time limit: a hung docker daemon
+        // fails the stage rather than stalling it.
+        let pull = async {
+            docker_pull_image(&job.source_image).await?;
+            let size = match docker_image_size(&job.source_image).await {
+                Ok(size) => Some(size),
+                Err(e) => {
+                    warn!(
+                        "⚠️ Could not read size of {} (skipping uncompressed size check): {}",
+                        job.source_image, e
+                    );
+                    None
+                }
+            };
+            // No platforms means the registry wouldn't let /notify inspect the manifest.
+            let platforms = if job.platforms.is_empty() {
+                match pulled_image_platforms(&job.source_image).await {
+                    Ok(platforms) => Some(platforms),
+                    Err(e) => {
+                        warn!(
+                            "⚠️ Could not read platforms of {} (skipping platform check): {}",
+                            job.source_image, e
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            Ok((size, platforms))
+        };
+        let (size, platforms) = match with_stage_timeout(jobs::JobStage::Pull, pull).await {
+            Ok(pulled) => pulled,
             Err(e) => {
-                warn!(
-                    "⚠️ Failed to resolve recipients from analytics/fallback list: {}",
-                    e
-                );
-                Vec::new()
+                error!("❌ Failed to pull source image {}: {}", job.source_image, e);
+                fail_job(&job, &workspace, e).await;
+                return;
             }
         };
 
-    if recipients.is_empty() {
-        info!("📭 No recipients resolved (no analytics email and no RESEND_TO_EMAILS fallback)");
-    } else {
-        info!(
-            "📬 Resolved {} recipient(s) for notifications",
-            recipients.len()
-        );
+        let rejection = size
+            .map(image_policy::check_uncompressed_size)
+            .and_then(Result::err)
+            .or_else(|| {
+                platforms
+                    .as_deref()
+                    .map(image_policy::check_platforms)
+                    .and_then(Result::err)
+            });
+        if let Some(rejection) = rejection {
+            warn!("🚫 Rejected {} after pull: {}", job.source_image, rejection);
+            remove_image(&job.source_image).await;
+            fail_job(&job, &workspace, rejection.to_string().into()).await;
+            return;
+        }
     }
 
-    // 5) Gate the background processing on a successful docker pull.
-    // This is the "docker pull the image in the json of the notification" step from feedback.
-    if let Err(e) = docker_pull_image(&source_image_with_digest).await {
-        error!(
-            "❌ Failed to pull source image {}, will not start background deployment: {}",
-            source_image_with_digest, e
-        );
-
-        let response = NotificationResponse {
-            message: format!("❌ Failed to pull image: {}", e),
-            container: target_image,
-            status: "Failed".to_string(),
-            timestamp,
-            lane_rpc_url: None,
+    // Background step 3: mirror/tag the pulled image into our stable registry.
+    if job.start <= PipelineStart::Mirror {
+        jobs::set_stage(&job.job_id, jobs::JobStage::Mirror);
+        let mirror = async {
+            let pushed = tag_and_push_to_registry(&job.source_image, &job.target_image).await?;
+            verify_mirrored_digest(&job.source_image, &job.target_image, &pushed, &job.digest)
+                .await?;
+            Ok(pushed)
         };
-
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(response));
+        match with_stage_timeout(jobs::JobStage::Mirror, mirror).await {
+            Ok(pushed) => jobs::set_mirror_digest(&job.job_id, &pushed),
+            Err(e) => {
+                error!(
+                    "❌ Failed to tag/push image for lane build: {} (image {})",
+                    e, job.target_image
+                );
+                fail_job(&job, &workspace, e).await;
+                return;
+            }
+        }
     }
 
-    // Move data into background job and return immediately.
-    let recipients_bg = recipients.clone();
-    let original_path = notification.original_path;
-    let registry_path = notification.registry_path;
-    let profile = notification.profile;
-    let platforms = notification.platforms;
-    let digest_owned = digest.to_string();
-    let target_image_bg = target_image.clone();
-    let source_image_bg = source_image_with_digest.clone();
-
-    tokio::spawn(async move {
-        // Serialize because lane build/export manipulates global docker/export state.
-        let _permit = build_job_semaphore().acquire().await;
-
-        // Background step 1: tell user we're starting deployment (only if we resolved a recipient).
-        if recipients_bg.is_empty() {
-            info!("📭 No recipient email resolved; skipping start/success emails");
-        } else if let Err(e) = email::send_lane_push_started_email(
-            &recipients_bg,
-            &original_path,
-            &registry_path,
-            Some(&digest_owned),
-            &profile,
-            &platforms,
-        )
-        .await
-        {
-            warn!("⚠️ Failed to send lane push started email: {}", e);
+    // Background step 4: lane build + export + sprite deployment.
+    // A previous job may already have uploaded this digest's export; then only the
+    // sprite needs deploying (unless the caller forced a rebuild).
+    let existing_export = if job.start > PipelineStart::Build || job.force {
+        None
+    } else {
+        match tigris::existing_export_keys(&job.digest, &job.profile).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                warn!(
+                    "⚠️ Could not check Tigris for an existing export (building anyway): {}",
+                    e
+                );
+                None
+            }
         }
+    };
 
-        // Background step 2: mirror/tag the pulled image into our stable registry.
-        if let Err(e) = tag_and_push_to_registry(&source_image_bg, &target_image_bg).await {
-            error!(
-                "❌ Failed to tag/push image for lane build: {} (image {})",
-                e, target_image_bg
-            );
+    if job.start == PipelineStart::Upload {
+        // The export finished before the restart; only the upload needs redoing.
+        if let Err(e) = upload_export(&job.job_id, workspace_ref, &job.digest, &job.profile).await {
+            warn!("⚠️ Upload of resumed export failed: {}", e);
+            fail_job(&job, &workspace, e).await;
             return;
         }
-
-        // Background step 3: lane build + export + sprite deployment.
-        if let Err(e) = run_lane_build(&target_image_bg).await {
+    } else if job.start > PipelineStart::Upload {
+        // Artifacts were uploaded before the restart.
+    } else if let Some(keys) = existing_export {
+        info!(
+            "♻️ Export for {} already in Tigris ({} files); skipping lane build/export",
+            job.digest,
+            keys.len()
+        );
+        jobs::set_artifact_keys(&job.job_id, &keys);
+    } else {
+        jobs::set_stage(&job.job_id, jobs::JobStage::Build);
+        if let Err(e) = with_stage_timeout(
+            jobs::JobStage::Build,
+            run_lane_build(&job.job_id, workspace_ref, &job.profile, &job.target_image),
+        )
+        .await
+        {
             error!("❌ Lane build failed in background job: {}", e);
+            fail_job(&job, &workspace, e).await;
             return;
         }
 
-        if let Err(e) = run_lane_export_and_upload(&digest_owned, &target_image_bg).await {
-            warn!("⚠️ Lane export failed in background job: {}", e);
+        jobs::set_stage(&job.job_id, jobs::JobStage::Export);
+        if let Err(e) = run_lane_export_and_upload(
+            &job.job_id,
+            workspace_ref,
+            &job.digest,
+            &job.profile,
+            &job.target_image,
+        )
+        .await
+        {
+            warn!("⚠️ Lane export or upload failed in background job: {}", e);
+            fail_job(&job, &workspace, e).await;
             return;
         }
+    }
 
-        let lane_rpc_url = match sprite::deploy_sprite(&digest_owned).await {
-            Ok(result) => {
-                info!(
-                    "✅ Sprite deployed: {} at {}",
-                    result.sprite_name, result.rpc_url
-                );
-                if let Err(e) = tigris::upsert_active_sprite(
-                    &result.sprite_name,
-                    &result.rpc_url,
-                    &digest_owned,
-                )
-                .await
-                {
-                    warn!("⚠️ Failed to upsert sprite active index in Tigris: {}", e);
-                }
-                Some(result.rpc_url)
-            }
-            Err(e) => {
-                warn!("⚠️ Sprite deploy failed (build/export succeeded): {}", e);
-                None
-            }
-        };
+    drop(docker_state);
 
-        // Background step 4: email only when RPC is actually available, and we have a recipient.
-        if recipients_bg.is_empty() {
-            // already logged above
-        } else if let Some(ref rpc_url) = lane_rpc_url {
-            if let Err(e) = email::send_lane_push_success_email(
-                &recipients_bg,
-                &target_image_bg,
-                &digest_owned,
-                Some(rpc_url),
+    let deployed = if job.start == PipelineStart::Email {
+        // Deployed before the restart; only the success email is left.
+        Ok(None)
+    } else {
+        jobs::set_stage(&job.job_id, jobs::JobStage::SpriteDeploy);
+        with_stage_timeout(
+            jobs::JobStage::SpriteDeploy,
+            sprite::deploy_sprite(&job.digest, &job.profile),
+        )
+        .await
+        .map(Some)
+    };
+    let lane_rpc_url = match deployed {
+        Ok(None) => job.lane_rpc_url.clone(),
+        Ok(Some(result)) => {
+            info!(
+                "✅ Sprite deployed: {} at {}",
+                result.sprite_name, result.rpc_url
+            );
+            jobs::set_lane_rpc_url(&job.job_id, &result.rpc_url);
+            if let Err(e) = tigris::upsert_sprite(
+                &result.sprite_name,
+                &result.rpc_url,
+                &job.digest,
+                &job.profile,
+                tigris::SPRITE_ACTIVE,
+                &job.job_id,
             )
             .await
             {
-                warn!("⚠️ Failed to send lane push success email: {}", e);
+                warn!("⚠️ Failed to upsert sprite active index in Tigris: {}", e);
             }
-        } else {
-            warn!("Lane build/export succeeded but RPC deploy failed; skipping RPC email");
+            Some(result.rpc_url)
+        }
+        Err(e) => {
+            warn!("⚠️ Sprite deploy failed (build/export succeeded): {}", e);
+            // Record a sprite whose RPC never came up, so it can be found and destroyed.
+            if let Some(not_ready) = e.downcast_ref::<sprite::LaneRpcNotReady>() {
+                if let Err(e) = tigris::upsert_sprite(
+                    &not_ready.sprite_name,
+                    &not_ready.rpc_url,
+                    &job.digest,
+                    &job.profile,
+                    tigris::SPRITE_STOPPED,
+                    &job.job_id,
+                )
+                .await
+                {
+                    warn!("⚠️ Failed to record unready sprite in Tigris: {}", e);
+                }
+            }
+            fail_job(&job, &workspace, e).await;
+            None
         }
-    });
-
-    let response = NotificationResponse {
-        message: "✅ Notification received; lane deployment queued".to_string(),
-        container: target_image,
-        status: "Queued".to_string(),
-        timestamp,
-        lane_rpc_url: None,
     };
 
-    (StatusCode::OK, Json(response))
+    // Background step 5: email only when RPC is actually available, and we have a recipient.
+    if lane_rpc_url.is_some() {
+        jobs::set_stage(&job.job_id, jobs::JobStage::Email);
+    }
+    if job.recipients.is_empty() {
+        // already logged above
+    } else if let Some(ref rpc_url) = lane_rpc_url {
+        if let Err(e) = email::send_lane_push_success_email(
+            &job.recipients,
+            &job.target_image,
+            &job.digest,
+            Some(rpc_url),
+        )
+        .await
+        {
+            warn!("⚠️ Failed to send lane push success email: {}", e);
+        }
+    } else {
+        warn!("Lane build/export succeeded but RPC deploy failed; skipping RPC email");
+    }
+
+    if lane_rpc_url.is_some() {
+        jobs::mark_succeeded(&job.job_id);
+    }
 }
 
 async fn log_disk_space(label: &str) {
@@ -508,54 +2215,266 @@ async fn wait_for_registry_login() {
     warn!("Registry login not confirmed within 60s (lane build may fail to fetch container)");
 }
 
+/// How long `lane --version` may take before the version is reported as unknown.
+const LANE_VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `lane --version`, cached once lane answers; "unknown" (asked again next time) if it
+/// can't say.
+async fn lane_cli_version() -> String {
+    static VERSION: OnceLock<String> = OnceLock::new();
+    if let Some(version) = VERSION.get() {
+        return version.clone();
+    }
+    let output = TokioCommand::new("lane")
+        .arg("--version")
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(LANE_VERSION_TIMEOUT, output).await {
+        Ok(Ok(output)) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if !version.is_empty() {
+                return VERSION.get_or_init(|| version).clone();
+            }
+        }
+        Ok(Ok(output)) => warn!("⚠️ lane --version exited with {}", output.status),
+        Ok(Err(e)) => warn!("⚠️ Could not run lane --version: {}", e),
+        Err(_) => warn!(
+            "⚠️ lane --version did not answer within {:?}",
+            LANE_VERSION_TIMEOUT
+        ),
+    }
+    "unknown".to_string()
+}
+
+/// `lane` invocation scoped to the job's workspace (HOME/cache/tmp) with piped output.
+///
+/// Runs in its own process group so a [`ProcessGroupGuard`] can take down everything it
+/// spawned, not just the node process itself.
+fn lane_command(workspace: &JobWorkspace) -> TokioCommand {
+    let mut cmd = TokioCommand::new("lane");
+    cmd.current_dir(workspace.root())
+        .env("HOME", workspace.home_dir())
+        .env("XDG_CACHE_HOME", workspace.cache_dir())
+        .env("TMPDIR", workspace.tmp_dir())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    cmd.process_group(0);
+    cmd
+}
+
+/// Kills the process group of a spawned `lane` command if dropped before [`disarm`].
+///
+/// That happens when a job is cancelled: its pipeline future is dropped mid-`wait()`, and
+/// this takes the docker/node children lane started down with it.
+///
+/// [`disarm`]: ProcessGroupGuard::disarm
+struct ProcessGroupGuard {
+    pgid: Option<u32>,
+}
+
+impl ProcessGroupGuard {
+    fn new(child: &tokio::process::Child) -> Self {
+        Self { pgid: child.id() }
+    }
+
+    /// The child exited on its own; nothing to kill.
+    fn disarm(mut self) {
+        self.pgid = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        let Some(pgid) = self.pgid else {
+            return;
+        };
+        warn!("🔪 Killing lane process group {}", pgid);
+        #[cfg(unix)]
+        // SAFETY: kill(2) has no memory-safety preconditions; a negative pid targets the
+        // process group we created for this child.
+        unsafe {
+            libc::kill(-(pgid as i32), libc::SIGKILL);
+        }
+    }
+}
+
+/// Force-remove containers that belong to the job: anything bind-mounting a path inside
+/// its workspace (lane mounts its cache/export dirs) or running the job's image.
+async fn remove_job_containers(workspace: &JobWorkspace, image: &str) {
+    let ids = match TokioCommand::new("docker")
+        .args(["ps", "-aq", "--no-trunc"])
+        .output()
+        .await
+    {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout)
+            .split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>(),
+        Ok(out) => {
+            warn!(
+                "Failed to list containers: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to list containers: {}", e);
+            return;
+        }
+    };
+    if ids.is_empty() {
+        return;
+    }
+
+    // A container can exit and vanish between `ps` and `inspect`; that only costs us its line.
+    let output = match TokioCommand::new("docker")
+        .args([
+            "inspect",
+            "--format",
+            "{{.Id}}\t{{.Config.Image}}\t{{json .Mounts}}",
+        ])
+        .args(&ids)
+        .output()
+        .await
+    {
+        Ok(out) => out,
+        Err(e) => {
+            warn!("Failed to inspect containers: {}", e);
+            return;
+        }
+    };
+
+    let root = workspace.root();
+    let ids: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let id = fields.next()?;
+            let container_image = fields.next()?;
+            let mounts: Vec<serde_json::Value> =
+                serde_json::from_str(fields.next()?).unwrap_or_default();
+            let mounts_workspace = mounts.iter().any(|m| {
+                m.get("Source")
+                    .and_then(|s| s.as_str())
+                    .is_some_and(|src| std::path::Path::new(src).starts_with(root))
+            });
+            (mounts_workspace || container_image == image).then(|| id.to_string())
+        })
+        .collect();
+
+    if ids.is_empty() {
+        return;
+    }
+
+    info!("🧹 Removing {} container(s) started by the job", ids.len());
+    match TokioCommand::new("docker")
+        .arg("rm")
+        .arg("-f")
+        .args(&ids)
+        .output()
+        .await
+    {
+        Ok(out) if !out.status.success() => warn!(
+            "Failed to remove containers: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to remove containers: {}", e),
+    }
+}
+
+/// A `lane` command exited unsuccessfully. Keeps the captured output so failure reports
+/// can quote it.
+#[derive(Debug)]
+struct LaneCommandFailed {
+    /// What ran, e.g. "Lane build".
+    what: &'static str,
+    status: std::process::ExitStatus,
+    stdout: String,
+    stderr: String,
+}
+
+impl LaneCommandFailed {
+    fn summary(&self) -> String {
+        format!("{} failed with exit code {}", self.what, self.status)
+    }
+
+    /// The last `max_lines` lines of stdout and of stderr, each under its own heading.
+    fn output_tail(&self, max_lines: usize) -> String {
+        let mut out = String::new();
+        for (name, text) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            let lines: Vec<&str> = text.trim().lines().collect();
+            if lines.is_empty() {
+                continue;
+            }
+            let start = lines.len().saturating_sub(max_lines);
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!(
+                "--- {} (last {} lines) ---\n",
+                name,
+                lines.len() - start
+            ));
+            out.push_str(&lines[start..].join("\n"));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl std::fmt::Display for LaneCommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_empty = |s: &str| {
+            if s.trim().is_empty() {
+                "(empty)".to_string()
+            } else {
+                s.trim().to_string()
+            }
+        };
+        write!(
+            f,
+            "{}.\nstdout: {}\nstderr: {}",
+            self.summary(),
+            or_empty(&self.stdout),
+            or_empty(&self.stderr)
+        )
+    }
+}
+
+impl std::error::Error for LaneCommandFailed {}
+
 async fn run_lane_build(
+    job_id: &str,
+    workspace: &JobWorkspace,
+    profile: &str,
     image_with_digest: &str,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    use tokio::io::AsyncReadExt;
-
     wait_for_docker().await?;
     wait_for_registry_login().await;
-    log_disk_space("before cleanup").await;
-    cleanup_docker().await;
-    log_disk_space_detail("after cleanup / before lane build").await;
-    info!("🚀 Starting Lane build with image: {}", image_with_digest);
-
-    let lane_home = std::env::var("LANE_HOME").unwrap_or_else(|_| "/data/lane-home".to_string());
-    let lane_cache =
-        std::env::var("XDG_CACHE_HOME").unwrap_or_else(|_| "/data/lane-cache".to_string());
-
-    let mut child = TokioCommand::new("lane")
-        .args(["build", "prod", "--image", image_with_digest])
-        .current_dir("/root")
-        .env("HOME", &lane_home)
-        .env("XDG_CACHE_HOME", &lane_cache)
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
-
-    let mut stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
-    let mut stderr = child.stderr.take().ok_or("Failed to capture stderr")?;
+    log_disk_space_detail("before lane build").await;
+    info!(
+        "🚀 Starting Lane build ({}) with image: {}",
+        profile, image_with_digest
+    );
 
-    let (stdout_tx, stdout_rx) = tokio::sync::oneshot::channel();
-    let (stderr_tx, stderr_rx) = tokio::sync::oneshot::channel();
+    let mut child = lane_command(workspace)
+        .args(["build", profile, "--image", image_with_digest])
+        .spawn()?;
+    let process_group = ProcessGroupGuard::new(&child);
 
-    tokio::spawn(async move {
-        let mut out = Vec::new();
-        let _ = stdout.read_to_end(&mut out).await;
-        let _ = stdout_tx.send(out);
-    });
-    tokio::spawn(async move {
-        let mut err = Vec::new();
-        let _ = stderr.read_to_end(&mut err).await;
-        let _ = stderr_tx.send(err);
-    });
+    let stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
+    let stderr = child.stderr.take().ok_or("Failed to capture stderr")?;
+    let stdout_task = forward_child_output(job_id, "lane build", LogStream::Stdout, stdout);
+    let stderr_task = forward_child_output(job_id, "lane build", LogStream::Stderr, stderr);
 
     let status = child.wait().await?;
+    process_group.disarm();
 
-    let stdout_bytes = stdout_rx.await.unwrap_or_default();
-    let stderr_bytes = stderr_rx.await.unwrap_or_default();
-    let stdout_str = String::from_utf8_lossy(&stdout_bytes);
-    let stderr_str = String::from_utf8_lossy(&stderr_bytes);
+    let stdout_str = stdout_task.await.unwrap_or_default();
+    let stderr_str = stderr_task.await.unwrap_or_default();
 
     log_disk_space("after lane build").await;
 
@@ -563,22 +2482,14 @@ async fn run_lane_build(
         info!("✅ Lane build completed successfully");
         Ok("Lane build completed successfully".to_string())
     } else {
-        let error_msg = format!(
-            "Lane build failed with exit code {}.\nstdout: {}\nstderr: {}",
+        let failed = LaneCommandFailed {
+            what: "Lane build",
             status,
-            if stdout_str.is_empty() {
-                "(empty)"
-            } else {
-                stdout_str.trim()
-            },
-            if stderr_str.is_empty() {
-                "(empty)"
-            } else {
-                stderr_str.trim()
-            }
-        );
-        error!("❌ {}", error_msg);
-        Err(error_msg.into())
+            stdout: stdout_str,
+            stderr: stderr_str,
+        };
+        error!("❌ {}", failed);
+        Err(failed.into())
     }
 }
 
@@ -691,12 +2602,56 @@ async fn async_main() {
         std::future::pending::<()>().await;
     });
 
+    if let Err(e) = jobs::init() {
+        let _ = std::io::stderr()
+            .write_all(format!("[ASYNC] Job store unavailable: {}\n", e).as_bytes());
+        let _ = std::io::stderr().flush();
+    } else {
+        tokio::spawn(resume_unfinished_jobs());
+    }
+
     let app = Router::new()
         .route("/health", get(health_handler))
         .route(
             "/notify",
             post(notify_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
         )
+        .route(
+            "/registry-events",
+            post(registry_events_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
+        )
+        .route(
+            "/jobs",
+            get(list_jobs_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
+        )
+        .route(
+            "/jobs/:id",
+            get(get_job_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
+        )
+        .route(
+            "/jobs/:id/cancel",
+            post(cancel_job_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
+        )
+        .route(
+            "/jobs/:id/events",
+            get(job_events_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
+        )
+        .route(
+            "/sprites",
+            get(list_sprites_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
+        )
+        .route(
+            "/sprites/history",
+            get(sprite_history_handler).route_layer(middleware::from_fn(notify_auth_middleware)),
+        )
+        .route(
+            "/sprites/:target/stop",
+            post(stop_sprite_handler).route_layer(middleware::from_fn(admin_auth_middleware)),
+        )
+        .route(
+            "/sprites/:target/destroy",
+            post(destroy_sprite_handler).route_layer(middleware::from_fn(admin_auth_middleware)),
+        )
         .fallback(not_found_handler)
         .layer(middleware::from_fn(logging_middleware));
 
@@ -794,45 +2749,137 @@ async fn async_main() {
     }
 }
 
-/// Export output directory. Must be absolute when using current_dir("/root") so upload can find it.
-const LANE_EXPORT_DIR: &str = "/root/lane-export-temp";
 async fn run_lane_export_and_upload(
+    job_id: &str,
+    workspace: &JobWorkspace,
     digest: &str,
+    profile: &str,
     image: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    info!("📤 Starting Lane export");
+    info!("📤 Starting Lane export ({})", profile);
 
+    // Absolute, since lane runs with the workspace root as its cwd.
+    let export_dir = workspace.export_dir();
+    let export_dir_str = export_dir.to_string_lossy().into_owned();
     // Clean up any previous export directory so we start fresh.
-    if tokio::fs::metadata(LANE_EXPORT_DIR).await.is_ok() {
-        info!("🧹 Removing previous {}...", LANE_EXPORT_DIR);
-        tokio::fs::remove_dir_all(LANE_EXPORT_DIR).await.ok();
-    }
-
-    let lane_home = std::env::var("LANE_HOME").unwrap_or_else(|_| "/data/lane-home".to_string());
-    let lane_cache =
-        std::env::var("XDG_CACHE_HOME").unwrap_or_else(|_| "/data/lane-cache".to_string());
-    let mut child = TokioCommand::new("lane")
-        .args(["export", "prod", LANE_EXPORT_DIR, "--image", image])
-        .current_dir("/root")
-        .env("HOME", &lane_home)
-        .env("XDG_CACHE_HOME", &lane_cache)
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
+    if tokio::fs::metadata(&export_dir).await.is_ok() {
+        info!("🧹 Removing previous {}...", export_dir_str);
+        tokio::fs::remove_dir_all(&export_dir).await.ok();
+    }
+
+    let mut child = lane_command(workspace)
+        .args(["export", profile, &export_dir_str, "--image", image])
         .spawn()?;
+    let process_group = ProcessGroupGuard::new(&child);
 
-    let status = child.wait().await?;
+    let stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
+    let stderr = child.stderr.take().ok_or("Failed to capture stderr")?;
+    let stdout_task = forward_child_output(job_id, "lane export", LogStream::Stdout, stdout);
+    let stderr_task = forward_child_output(job_id, "lane export", LogStream::Stderr, stderr);
+
+    let status =
+        with_stage_timeout(jobs::JobStage::Export, async { Ok(child.wait().await?) }).await?;
+    process_group.disarm();
+    let stdout_str = stdout_task.await.unwrap_or_default();
+    let stderr_str = stderr_task.await.unwrap_or_default();
 
     if !status.success() {
-        return Err(format!("Lane export failed with exit code {}", status).into());
+        return Err(LaneCommandFailed {
+            what: "Lane export",
+            status,
+            stdout: stdout_str,
+            stderr: stderr_str,
+        }
+        .into());
     }
 
     info!("✅ Lane export completed successfully");
-    info!("☁️ Starting upload to Tigris S3");
+    upload_export(job_id, workspace, digest, profile).await
+}
 
-    tigris::upload_to_tigris(digest, LANE_EXPORT_DIR).await?;
+/// Upload the job's export directory to Tigris and record the uploaded keys.
+async fn upload_export(
+    job_id: &str,
+    workspace: &JobWorkspace,
+    digest: &str,
+    profile: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("☁️ Starting upload to Tigris S3");
+    jobs::set_stage(job_id, jobs::JobStage::Upload);
+
+    let export_dir = workspace.export_dir();
+    let export_dir_str = export_dir.to_string_lossy().into_owned();
+    let uploaded_keys = match with_stage_timeout(
+        jobs::JobStage::Upload,
+        tigris::upload_to_tigris(
+            digest,
+            profile,
+            &export_dir_str,
+            &workspace.upload_state_dir(),
+            &lane_cli_version().await,
+        ),
+    )
+    .await
+    {
+        Ok(keys) => keys,
+        Err(e) => {
+            // Keep the record exact about what did and didn't reach Tigris.
+            if let Some(incomplete) = e.downcast_ref::<tigris::UploadIncomplete>() {
+                jobs::set_artifact_keys(job_id, &incomplete.uploaded_keys);
+                jobs::set_failed_artifacts(job_id, &incomplete.failed_files());
+            }
+            return Err(e);
+        }
+    };
+    jobs::set_artifact_keys(job_id, &uploaded_keys);
+    jobs::set_failed_artifacts(job_id, &[]);
 
-    info!("🧹 Cleaning up {} after upload...", LANE_EXPORT_DIR);
-    tokio::fs::remove_dir_all(LANE_EXPORT_DIR).await.ok();
+    info!("🧹 Cleaning up {} after upload...", export_dir_str);
+    tokio::fs::remove_dir_all(&export_dir).await.ok();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_digest_from_push_output() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let output = format!(
+            "The push refers to repository [cli-backend-registry.fly.dev/lane-abc]\n\
+             5f70bf18a086: Pushed\n\
+             latest: digest: {} size: 528\n",
+            digest
+        );
+        assert_eq!(pushed_digest(&output), Some(digest));
+        assert_eq!(pushed_digest("5f70bf18a086: Pushed\n"), None);
+        assert_eq!(pushed_digest("latest: digest: sha256:short size: 1"), None);
+    }
+
+    #[test]
+    fn mirror_of_an_index_source_stays_ours() {
+        let index = format!("sha256:{}", "1".repeat(64));
+        let pushed = format!("sha256:{}", "2".repeat(64));
+        let other = format!("sha256:{}", "3".repeat(64));
+
+        // The tag holds what an earlier mirror of this index pushed.
+        assert!(matches!(
+            mirror_owner(pushed.clone(), &index, std::slice::from_ref(&pushed)),
+            naming::Owner::Same
+        ));
+        assert!(matches!(
+            mirror_owner(index.clone(), &index, &[]),
+            naming::Owner::Same
+        ));
+        assert!(matches!(
+            mirror_owner(pushed.clone(), &index, &[]),
+            naming::Owner::Other(d) if d == pushed
+        ));
+        assert!(matches!(
+            mirror_owner(other.clone(), &index, std::slice::from_ref(&pushed)),
+            naming::Owner::Other(d) if d == other
+        ));
+    }
+}
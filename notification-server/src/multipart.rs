@@ -0,0 +1,421 @@
+//! Streaming uploads of export files to Tigris.
+//!
+//! Files up to one part in size go up with a single `PUT`. Larger ones (the multi-GB
+//! squashfs snapshot) use an S3 multipart upload: the file is read one part at a time,
+//! at most `LANE_UPLOAD_PARALLEL_PARTS` (default 4) parts are in flight at once, and each
+//! part is retried `LANE_UPLOAD_PART_RETRIES` (default 3) times with backoff. Memory use
+//! stays around `parallel parts × LANE_UPLOAD_PART_SIZE_MB` (default 32 MiB, minimum 5).
+//!
+//! Progress (upload id and the ETag of every stored part) is saved after each part in a
+//! small state file, so an upload interrupted by a restart continues where it stopped
+//! instead of starting over. A state file is only reused for the same key, file size,
+//! modification time and part size.
+
+use s3::command::{Command, Multipart};
+use s3::request::Reqwest;
+use s3::request_trait::Request;
+use s3::serde_types::{CompleteMultipartUploadData, Part};
+use s3::Bucket;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const DEFAULT_PART_SIZE_MB: u64 = 32;
+const DEFAULT_PARALLEL_PARTS: usize = 4;
+const DEFAULT_PART_RETRIES: u32 = 3;
+
+type UploadError = Box<dyn std::error::Error + Send + Sync>;
+
+fn part_size() -> u64 {
+    let mb = std::env::var("LANE_UPLOAD_PART_SIZE_MB")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_PART_SIZE_MB);
+    (mb * 1024 * 1024).max(MIN_PART_SIZE)
+}
+
+fn parallel_parts() -> usize {
+    std::env::var("LANE_UPLOAD_PARALLEL_PARTS")
+        .ok()
+        .and_then(|s| s.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_PARALLEL_PARTS)
+}
+
+fn part_retries() -> u32 {
+    std::env::var("LANE_UPLOAD_PART_RETRIES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_PART_RETRIES)
+}
+
+/// A multipart upload in progress, as saved between parts.
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadState {
+    key: String,
+    upload_id: String,
+    file_size: u64,
+    modified_unix_nanos: u128,
+    part_size: u64,
+    /// Stored parts as `(part number, ETag)`.
+    parts: Vec<(u32, String)>,
+}
+
+fn state_path(state_dir: &Path, key: &str) -> PathBuf {
+    let name = hex::encode(&Sha256::digest(key.as_bytes())[..8]);
+    state_dir.join(format!("{}.json", name))
+}
+
+async fn load_state(path: &Path) -> Option<UploadState> {
+    let bytes = tokio::fs::read(path).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+async fn save_state(path: &Path, state: &UploadState) -> Result<(), UploadError> {
+    // Written aside and renamed, so a crash mid-write never leaves a truncated state.
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(state)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Upload `file_path` to `key`, keeping multipart progress under `state_dir`.
+///
+/// Returns the file's size and hex SHA-256 (computed while reading it).
+pub async fn upload_file(
+    bucket: &Bucket,
+    file_path: &Path,
+    key: &str,
+    content_type: &str,
+    state_dir: &Path,
+) -> Result<(u64, String), UploadError> {
+    let metadata = tokio::fs::metadata(file_path)
+        .await
+        .map_err(|e| format!("Failed to get metadata for file: {:?}: {}", file_path, e))?;
+    let file_size = metadata.len();
+    let part_size = part_size();
+
+    info!(
+        "Uploading file: {} (size: {} bytes)",
+        file_path.display(),
+        file_size
+    );
+
+    if file_size <= part_size {
+        let content = tokio::fs::read(file_path)
+            .await
+            .map_err(|e| format!("Failed to read file: {:?}: {}", file_path, e))?;
+        let sha256 = hex::encode(Sha256::digest(&content));
+        let response = bucket
+            .put_object_with_content_type(key, &content, content_type)
+            .await
+            .map_err(|e| format!("Failed to upload to s3://{}/{}: {}", bucket.name(), key, e))?;
+        if response.status_code() != 200 {
+            return Err(
+                format!("Upload failed with status code: {}", response.status_code()).into(),
+            );
+        }
+        return Ok((file_size, sha256));
+    }
+
+    let modified_unix_nanos = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    tokio::fs::create_dir_all(state_dir).await?;
+    let state_file = state_path(state_dir, key);
+
+    let resumable = load_state(&state_file).await.filter(|s| {
+        s.key == key
+            && s.file_size == file_size
+            && s.modified_unix_nanos == modified_unix_nanos
+            && s.part_size == part_size
+    });
+    let state = match resumable {
+        Some(state) => {
+            info!(
+                "Resuming multipart upload of {} ({} parts already stored)",
+                key,
+                state.parts.len()
+            );
+            state
+        }
+        None => {
+            let upload_id = initiate(bucket, key, content_type).await?;
+            let state = UploadState {
+                key: key.to_string(),
+                upload_id,
+                file_size,
+                modified_unix_nanos,
+                part_size,
+                parts: Vec::new(),
+            };
+            save_state(&state_file, &state).await?;
+            state
+        }
+    };
+
+    let upload_id = state.upload_id.clone();
+    match upload_parts(bucket, file_path, content_type, &state_file, state).await {
+        Ok(done) => {
+            tokio::fs::remove_file(&state_file).await.ok();
+            Ok(done)
+        }
+        Err(e) => {
+            // Out of retries: drop the partial object rather than leave it for a restart
+            // that isn't coming.
+            warn!("Aborting multipart upload of {}: {}", key, e);
+            if let Err(abort) = bucket.abort_upload(key, &upload_id).await {
+                warn!("Failed to abort multipart upload of {}: {}", key, abort);
+            }
+            tokio::fs::remove_file(&state_file).await.ok();
+            Err(e)
+        }
+    }
+}
+
+async fn upload_parts(
+    bucket: &Bucket,
+    file_path: &Path,
+    content_type: &str,
+    state_file: &Path,
+    mut state: UploadState,
+) -> Result<(u64, String), UploadError> {
+    let total_parts = state.file_size.div_ceil(state.part_size) as u32;
+    let max_in_flight = parallel_parts();
+    let retries = part_retries();
+
+    let mut file = tokio::fs::File::open(file_path)
+        .await
+        .map_err(|e| format!("Failed to open file: {:?}: {}", file_path, e))?;
+    let mut hasher = Sha256::new();
+    let mut in_flight: JoinSet<Result<(u32, String), UploadError>> = JoinSet::new();
+
+    for part_number in 1..=total_parts {
+        let mut chunk = Vec::with_capacity(state.part_size as usize);
+        (&mut file)
+            .take(state.part_size)
+            .read_to_end(&mut chunk)
+            .await
+            .map_err(|e| format!("Failed to read file: {:?}: {}", file_path, e))?;
+        hasher.update(&chunk);
+
+        if state.parts.iter().any(|(n, _)| *n == part_number) {
+            continue;
+        }
+
+        while in_flight.len() >= max_in_flight {
+            record_part(&mut in_flight, &mut state, state_file).await?;
+        }
+        let bucket = bucket.clone();
+        let key = state.key.clone();
+        let upload_id = state.upload_id.clone();
+        let content_type = content_type.to_string();
+        in_flight.spawn(async move {
+            let etag = put_part(
+                &bucket,
+                &key,
+                &upload_id,
+                &content_type,
+                part_number,
+                &chunk,
+                retries,
+            )
+            .await?;
+            Ok((part_number, etag))
+        });
+    }
+    while !in_flight.is_empty() {
+        record_part(&mut in_flight, &mut state, state_file).await?;
+    }
+
+    state.parts.sort_by_key(|(n, _)| *n);
+    complete(bucket, &state).await?;
+    info!(
+        "Completed multipart upload of {} ({} parts)",
+        state.key, total_parts
+    );
+    Ok((state.file_size, hex::encode(hasher.finalize())))
+}
+
+/// Wait for one in-flight part and save it to the upload state.
+async fn record_part(
+    in_flight: &mut JoinSet<Result<(u32, String), UploadError>>,
+    state: &mut UploadState,
+    state_file: &Path,
+) -> Result<(), UploadError> {
+    let Some(joined) = in_flight.join_next().await else {
+        return Ok(());
+    };
+    let (part_number, etag) = joined??;
+    state.parts.push((part_number, etag));
+    save_state(state_file, state).await
+}
+
+async fn initiate(bucket: &Bucket, key: &str, content_type: &str) -> Result<String, UploadError> {
+    let request = Reqwest::new(
+        bucket,
+        key,
+        Command::InitiateMultipartUpload { content_type },
+    );
+    let response = request.response_data(false).await?;
+    let body = String::from_utf8_lossy(response.bytes());
+    if response.status_code() >= 300 {
+        return Err(format!(
+            "initiating multipart upload of {} failed: status {}: {}",
+            key,
+            response.status_code(),
+            body
+        )
+        .into());
+    }
+    xml_element(&body, "UploadId")
+        .map(str::to_string)
+        .ok_or_else(|| format!("no UploadId in response for {}: {}", key, body).into())
+}
+
+async fn put_part(
+    bucket: &Bucket,
+    key: &str,
+    upload_id: &str,
+    content_type: &str,
+    part_number: u32,
+    chunk: &[u8],
+    retries: u32,
+) -> Result<String, UploadError> {
+    let mut attempt = 0;
+    loop {
+        let request = Reqwest::new(
+            bucket,
+            key,
+            Command::PutObject {
+                content: chunk,
+                content_type,
+                multipart: Some(Multipart::new(part_number, upload_id)),
+            },
+        );
+        let result = match request.response_data(true).await {
+            Ok(response) if (200..300).contains(&response.status_code()) => {
+                Ok(String::from_utf8_lossy(response.bytes()).to_string())
+            }
+            Ok(response) => Err(format!("status {}", response.status_code())),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(etag) => return Ok(etag),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                let delay = Duration::from_secs(1 << attempt.min(5));
+                warn!(
+                    "Part {} of {} failed ({}); retry {}/{} in {:?}",
+                    part_number, key, e, attempt, retries, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                return Err(format!(
+                    "part {} of {} failed after {} attempts: {}",
+                    part_number,
+                    key,
+                    attempt + 1,
+                    e
+                )
+                .into())
+            }
+        }
+    }
+}
+
+async fn complete(bucket: &Bucket, state: &UploadState) -> Result<(), UploadError> {
+    let parts = state
+        .parts
+        .iter()
+        .map(|(part_number, etag)| Part {
+            part_number: *part_number,
+            etag: etag.clone(),
+        })
+        .collect();
+    let request = Reqwest::new(
+        bucket,
+        &state.key,
+        Command::CompleteMultipartUpload {
+            upload_id: &state.upload_id,
+            data: CompleteMultipartUploadData { parts },
+        },
+    );
+    let response = request.response_data(false).await?;
+    let body = String::from_utf8_lossy(response.bytes());
+    // S3 can report a failed completion in the body of a 200.
+    if response.status_code() >= 300 || body.contains("<Error>") {
+        return Err(format!(
+            "completing multipart upload of {} failed: status {}: {}",
+            state.key,
+            response.status_code(),
+            body
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(&xml[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_xml_elements() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Bucket>lane-exports</Bucket>
+  <Key>sha256:abc/vc-cm-snapshot.squashfs</Key>
+  <UploadId>2~upload-id</UploadId>
+</InitiateMultipartUploadResult>"#;
+        assert_eq!(xml_element(xml, "UploadId"), Some("2~upload-id"));
+        assert_eq!(xml_element(xml, "Bucket"), Some("lane-exports"));
+        assert_eq!(xml_element(xml, "ETag"), None);
+        assert_eq!(xml_element("<UploadId>unterminated", "UploadId"), None);
+    }
+
+    #[test]
+    fn state_files_are_per_key() {
+        let dir = Path::new("/tmp/state");
+        assert_eq!(state_path(dir, "a"), state_path(dir, "a"));
+        assert_ne!(state_path(dir, "a"), state_path(dir, "b"));
+        assert_eq!(state_path(dir, "a").parent(), Some(dir));
+    }
+
+    #[tokio::test]
+    async fn saved_state_loads_back() {
+        let dir = std::env::temp_dir().join(format!("multipart-test-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = state_path(&dir, "key");
+        let state = UploadState {
+            key: "key".to_string(),
+            upload_id: "upload".to_string(),
+            file_size: 42,
+            modified_unix_nanos: 7,
+            part_size: MIN_PART_SIZE,
+            parts: vec![(1, "\"etag-1\"".to_string())],
+        };
+        save_state(&path, &state).await.unwrap();
+        let loaded = load_state(&path).await.unwrap();
+        assert_eq!(loaded.upload_id, "upload");
+        assert_eq!(loaded.parts, state.parts);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! Names derived from an image digest: the mirrored repository in our registry
+//! (`lane-<hex>:latest`) and the Sprite that serves the build (`lane-<hex>[-<profile>]`).
+//!
+//! Both start from the first 12 hex digits of the digest. Before a name
+//! is reused, the caller checks what it currently belongs to (the registry tag's manifest
+//! digest, the sprite index record's digest); if that is a different digest, the next,
+//! longer prefix is tried, up to the full 64 digits.
+
+use tracing::warn;
+
+/// Prefix shared by every derived name.
+pub const PREFIX: &str = "lane-";
+
+/// Hex digits of the digest to use, shortest first.
+const NAME_LENGTHS: &[usize] = &[12, 16, 24, 32, 64];
+
+/// Mirror repositories created before names were collision-checked used 8 digits.
+const LEGACY_LENGTH: usize = 8;
+
+/// The 64 lowercase hex digits of a `sha256:` digest, if it is one.
+pub fn digest_hex(digest: &str) -> Option<&str> {
+    let hex = digest.strip_prefix("sha256:")?;
+    (hex.len() == 64
+        && hex
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)))
+    .then_some(hex)
+}
+
+/// Candidate base names for `digest`, shortest first. Empty if it isn't a sha256 digest.
+pub fn candidates(digest: &str) -> Vec<String> {
+    let Some(hex) = digest_hex(digest) else {
+        return Vec::new();
+    };
+    NAME_LENGTHS
+        .iter()
+        .map(|&len| format!("{}{}", PREFIX, &hex[..len]))
+        .collect()
+}
+
+/// Whether `name` looks like a base name this module produced (including legacy ones).
+pub fn is_derived_name(name: &str) -> bool {
+    name.strip_prefix(PREFIX).is_some_and(|rest| {
+        (rest.len() == LEGACY_LENGTH || NAME_LENGTHS.contains(&rest.len()))
+            && rest.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+/// Who currently holds a candidate name.
+pub enum Owner {
+    /// Nobody: the name is free.
+    Free,
+    /// Something built from this full digest.
+    Same,
+    /// Something built from another digest sharing the prefix.
+    Other(String),
+}
+
+/// Pick the first candidate that is free or already ours, asking `owner_of` about each.
+///
+/// Errors if `digest` isn't a sha256 digest, `owner_of` fails, or (only possible for an
+/// identical digest) every candidate is taken.
+pub async fn choose<F, Fut>(
+    digest: &str,
+    what: &str,
+    mut owner_of: F,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: FnMut(String) -> Fut,
+    Fut: std::future::Future<Output = Result<Owner, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let names = candidates(digest);
+    if names.is_empty() {
+        return Err(format!("cannot derive a {} name from digest {}", what, digest).into());
+    }
+    for name in names {
+        match owner_of(name.clone()).await? {
+            Owner::Free | Owner::Same => return Ok(name),
+            Owner::Other(other) => {
+                warn!(
+                    "⚠️ {} name {} already belongs to {}; trying a longer name for {}",
+                    what, name, other, digest
+                );
+            }
+        }
+    }
+    Err(format!("every {} name for {} is taken", what, digest).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(hex: &str) -> String {
+        format!("sha256:{}", hex)
+    }
+
+    #[test]
+    fn reads_sha256_hex() {
+        let hex = "0123456789abcdef".repeat(4);
+        assert_eq!(digest_hex(&digest(&hex)), Some(hex.as_str()));
+        assert_eq!(digest_hex(&hex), None);
+        assert_eq!(digest_hex(&digest(&hex[..63])), None);
+        assert_eq!(digest_hex(&digest(&hex.to_uppercase())), None);
+        assert_eq!(digest_hex(&format!("sha512:{}", hex)), None);
+    }
+
+    #[test]
+    fn candidates_grow_to_the_full_digest() {
+        let hex = "0123456789abcdef".repeat(4);
+        let names = candidates(&digest(&hex));
+        assert_eq!(names.first().unwrap(), "lane-0123456789ab");
+        assert_eq!(names.last().unwrap(), &format!("lane-{}", hex));
+        assert_eq!(names.len(), NAME_LENGTHS.len());
+        assert!(names.iter().all(|name| is_derived_name(name)));
+        assert!(candidates("latest").is_empty());
+    }
+
+    #[test]
+    fn recognises_derived_names() {
+        assert!(is_derived_name("lane-0123abcd"));
+        assert!(is_derived_name("lane-0123456789ab"));
+        assert!(!is_derived_name("lane-0123456789a"));
+        assert!(!is_derived_name("lane-0123456789xy"));
+        assert!(!is_derived_name("myapp"));
+    }
+
+    #[tokio::test]
+    async fn choose_skips_names_owned_by_other_digests() {
+        let hex = "0123456789abcdef".repeat(4);
+        let chosen = choose(&digest(&hex), "test", |name| async move {
+            Ok(if name == "lane-0123456789ab" {
+                Owner::Other(digest(&"0".repeat(64)))
+            } else {
+                Owner::Free
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(chosen, "lane-0123456789abcdef");
+
+        let same = choose(&digest(&hex), "test", |_| async { Ok(Owner::Same) })
+            .await
+            .unwrap();
+        assert_eq!(same, "lane-0123456789ab");
+    }
+
+    #[tokio::test]
+    async fn choose_fails_when_every_name_is_taken() {
+        let hex = "0123456789abcdef".repeat(4);
+        let taken = choose(&digest(&hex), "test", |_| async {
+            Ok(Owner::Other("sha256:other".to_string()))
+        })
+        .await;
+        assert!(taken.is_err());
+        assert!(choose("latest", "test", |_| async { Ok(Owner::Free) })
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,491 @@
+//! Minimal client for the registry HTTP API (OCI distribution spec): enough to resolve a
+//! tag's manifest digest and read manifests and config blobs without pulling the image.
+//!
+//! Requests start out anonymous. On a `401` we answer the registry's challenge and retry
+//! once: `Basic` with our credentials (our own htpasswd registry), or `Bearer` with a pull
+//! token from the advertised realm (Docker Hub, GHCR, ...), fetched with our credentials
+//! when we have some for that host and anonymously otherwise.
+//!
+//! The only credentials we hold are for our own registry (`LANE_REGISTRY_BASE`):
+//! `REGISTRY_USERNAME` (default `lane-container`) and `REGISTRY_PASSWORD`, the same login
+//! start.sh gives docker.
+
+use reqwest::{header, Client, Method, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+
+/// Manifest types we accept, in order of preference.
+pub const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+application/vnd.docker.distribution.manifest.list.v2+json, \
+application/vnd.oci.image.manifest.v1+json, \
+application/vnd.docker.distribution.manifest.v2+json";
+
+const DOCKER_V2_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Our own registry, where mirrored images live and registry-event pushes are pulled from.
+pub fn own_registry_base() -> String {
+    std::env::var("LANE_REGISTRY_BASE")
+        .unwrap_or_else(|_| "cli-backend-registry.fly.dev".to_string())
+}
+
+/// Username and password for a registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// Credentials we hold for `host`, if any.
+fn credentials_for(host: &str) -> Option<Credentials> {
+    if host != own_registry_base() {
+        return None;
+    }
+    let password = std::env::var("REGISTRY_PASSWORD")
+        .ok()
+        .filter(|p| !p.is_empty())?;
+    let username =
+        std::env::var("REGISTRY_USERNAME").unwrap_or_else(|_| "lane-container".to_string());
+    Some(Credentials { username, password })
+}
+
+/// The registry refused us (`401`/`403`) even after answering its challenge, or asked for
+/// credentials we don't have.
+#[derive(Debug)]
+pub struct Unauthorized {
+    pub url: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "registry refused {}: {}", self.url, self.reason)
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+/// Whether `e` is an [`Unauthorized`].
+pub fn is_unauthorized(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<Unauthorized>().is_some()
+}
+
+/// A parsed image reference: `host/repository` plus a tag or digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    /// Registry host as written (`docker.io` for references without one).
+    pub host: String,
+    /// Repository path, e.g. `library/alpine` or `lanelayer/app`.
+    pub repository: String,
+    /// Tag, or digest for `name@sha256:...` references.
+    pub reference: String,
+}
+
+impl ImageRef {
+    /// Parse `[host/]repo[:tag][@digest]` the way docker does: the first path component
+    /// is a host only if it has a `.` or `:` or is `localhost`.
+    pub fn parse(image: &str) -> Result<Self, String> {
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (image, None),
+        };
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((n, t)) if !t.contains('/') => (n, Some(t)),
+            _ => (name, None),
+        };
+        if name.is_empty() {
+            return Err(format!("invalid image reference: {}", image));
+        }
+
+        let (host, repository) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first.to_string(), rest.to_string())
+            }
+            _ => ("docker.io".to_string(), name.to_string()),
+        };
+        let repository = if host == "docker.io" && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        let reference = digest.or(tag).unwrap_or("latest").to_string();
+        Ok(Self {
+            host,
+            repository,
+            reference,
+        })
+    }
+
+    fn api_base(&self) -> String {
+        let host = if self.host == "docker.io" {
+            "registry-1.docker.io"
+        } else {
+            self.host.as_str()
+        };
+        // Like docker, talk plain HTTP only to a registry on this machine.
+        let scheme = if host.starts_with("localhost") || host.starts_with("127.0.0.1") {
+            "http"
+        } else {
+            "https"
+        };
+        format!("{}://{}/v2/{}", scheme, host, self.repository)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+/// A `WWW-Authenticate` challenge.
+#[derive(Debug, PartialEq, Eq)]
+enum Challenge {
+    Basic,
+    /// `realm`, `service`, `scope`, ... as given.
+    Bearer(Vec<(String, String)>),
+}
+
+fn parse_challenge(challenge: &str) -> Option<Challenge> {
+    let challenge = challenge.trim();
+    let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+    if scheme.eq_ignore_ascii_case("basic") {
+        Some(Challenge::Basic)
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        parse_challenge_params(params).map(Challenge::Bearer)
+    } else {
+        None
+    }
+}
+
+/// Parse `realm="...",service="...",scope="..."` into its parameters.
+fn parse_challenge_params(params: &str) -> Option<Vec<(String, String)>> {
+    let mut out = Vec::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let (key, after_key) = rest.split_once('=')?;
+        let after_key = after_key.trim_start();
+        let (value, after_value) = if let Some(quoted) = after_key.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = after_key.find(',').unwrap_or(after_key.len());
+            (&after_key[..end], &after_key[end..])
+        };
+        out.push((key.trim().to_string(), value.to_string()));
+        rest = after_value.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+    Some(out)
+}
+
+/// How to authenticate the retry of a request that got a `401`.
+#[derive(Debug, PartialEq, Eq)]
+enum RetryAuth {
+    Basic(Credentials),
+    Token {
+        realm: String,
+        query: Vec<(String, String)>,
+        credentials: Option<Credentials>,
+    },
+}
+
+fn retry_auth(
+    challenge: &str,
+    credentials: Option<Credentials>,
+) -> Result<RetryAuth, Box<dyn std::error::Error + Send + Sync>> {
+    match parse_challenge(challenge) {
+        Some(Challenge::Basic) => credentials
+            .map(RetryAuth::Basic)
+            .ok_or_else(|| "registry wants Basic credentials and we have none".into()),
+        Some(Challenge::Bearer(params)) => {
+            let realm = params
+                .iter()
+                .find(|(k, _)| k == "realm")
+                .map(|(_, v)| v.clone())
+                .ok_or("auth challenge has no realm")?;
+            let query = params.into_iter().filter(|(k, _)| k != "realm").collect();
+            Ok(RetryAuth::Token {
+                realm,
+                query,
+                credentials,
+            })
+        }
+        None => Err(format!("unsupported auth challenge: {}", challenge).into()),
+    }
+}
+
+async fn pull_token(
+    client: &Client,
+    realm: &str,
+    query: &[(String, String)],
+    credentials: Option<&Credentials>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut request = client.get(realm).query(query);
+    if let Some(c) = credentials {
+        request = request.basic_auth(&c.username, Some(&c.password));
+    }
+    let response = request.send().await?;
+    if matches!(
+        response.status(),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+    ) {
+        return Err(Unauthorized {
+            url: realm.to_string(),
+            reason: format!("token request failed: HTTP {}", response.status()),
+        }
+        .into());
+    }
+    if !response.status().is_success() {
+        return Err(format!(
+            "token request to {} failed: HTTP {}",
+            realm,
+            response.status()
+        )
+        .into());
+    }
+    let body: TokenResponse = response.json().await?;
+    body.token
+        .or(body.access_token)
+        .ok_or_else(|| "token response had no token".into())
+}
+
+/// `GET`/`HEAD` `url`, answering an auth challenge once. A request still refused after
+/// that is an [`Unauthorized`] error.
+async fn send(
+    host: &str,
+    method: Method,
+    url: &str,
+    accept: &str,
+) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+    let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+    let request = || {
+        client
+            .request(method.clone(), url)
+            .header(header::ACCEPT, accept)
+    };
+    let response = request().send().await?;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return refused_as_error(url, response);
+    }
+
+    let challenge = response
+        .headers()
+        .get(header::WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let auth = retry_auth(&challenge, credentials_for(host)).map_err(|e| Unauthorized {
+        url: url.to_string(),
+        reason: e.to_string(),
+    })?;
+    let response = match auth {
+        RetryAuth::Basic(c) => {
+            request()
+                .basic_auth(&c.username, Some(&c.password))
+                .send()
+                .await?
+        }
+        RetryAuth::Token {
+            realm,
+            query,
+            credentials,
+        } => {
+            let token = pull_token(&client, &realm, &query, credentials.as_ref()).await?;
+            request().bearer_auth(token).send().await?
+        }
+    };
+    refused_as_error(url, response)
+}
+
+fn refused_as_error(
+    url: &str,
+    response: Response,
+) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+    if matches!(
+        response.status(),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+    ) {
+        return Err(Unauthorized {
+            url: url.to_string(),
+            reason: format!("HTTP {}", response.status()),
+        }
+        .into());
+    }
+    Ok(response)
+}
+
+/// Digest of the manifest `image` currently points at (`Docker-Content-Digest`).
+pub async fn manifest_digest(
+    image: &ImageRef,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    manifest_digest_if_exists(image).await?.ok_or_else(|| {
+        format!(
+            "resolving {}/{}:{} failed: not found",
+            image.host, image.repository, image.reference
+        )
+        .into()
+    })
+}
+
+/// Like [`manifest_digest`], but `None` when the repository or tag doesn't exist.
+pub async fn manifest_digest_if_exists(
+    image: &ImageRef,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("{}/manifests/{}", image.api_base(), image.reference);
+    let response = send(&image.host, Method::HEAD, &url, MANIFEST_ACCEPT).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(format!("resolving {} failed: HTTP {}", url, response.status()).into());
+    }
+    response
+        .headers()
+        .get("docker-content-digest")
+        .and_then(|v| v.to_str().ok())
+        .map(|d| Some(d.to_string()))
+        .ok_or_else(|| format!("registry returned no Docker-Content-Digest for {}", url).into())
+}
+
+/// Fetch the manifest (or index) for `reference` in `image`'s repository.
+pub async fn manifest(
+    image: &ImageRef,
+    reference: &str,
+) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("{}/manifests/{}", image.api_base(), reference);
+    let response = send(&image.host, Method::GET, &url, MANIFEST_ACCEPT).await?;
+    if !response.status().is_success() {
+        return Err(format!("fetching {} failed: HTTP {}", url, response.status()).into());
+    }
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+/// Whether `manifest` is a single-platform docker v2 manifest (not an index, manifest
+/// list or OCI manifest).
+pub fn is_docker_v2_manifest(manifest: &Value) -> bool {
+    manifest.get("mediaType").and_then(|m| m.as_str()) == Some(DOCKER_V2_MANIFEST)
+}
+
+/// Fetch a JSON blob (e.g. an image config) from `image`'s repository.
+pub async fn blob_json(
+    image: &ImageRef,
+    digest: &str,
+) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("{}/blobs/{}", image.api_base(), digest);
+    let response = send(&image.host, Method::GET, &url, "*/*").await?;
+    if !response.status().is_success() {
+        return Err(format!("fetching {} failed: HTTP {}", url, response.status()).into());
+    }
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds() -> Credentials {
+        Credentials {
+            username: "lane-container".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_bearer_challenge() {
+        let challenge = r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#;
+        assert_eq!(
+            parse_challenge(challenge),
+            Some(Challenge::Bearer(vec![
+                ("realm".into(), "https://auth.docker.io/token".into()),
+                ("service".into(), "registry.docker.io".into()),
+                ("scope".into(), "repository:library/alpine:pull".into()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn parses_basic_challenge() {
+        assert_eq!(
+            parse_challenge(r#"Basic realm="basic-realm""#),
+            Some(Challenge::Basic)
+        );
+        assert_eq!(parse_challenge("basic"), Some(Challenge::Basic));
+        assert_eq!(parse_challenge(r#"Digest realm="x""#), None);
+    }
+
+    #[test]
+    fn basic_challenge_uses_credentials() {
+        assert_eq!(
+            retry_auth(r#"Basic realm="basic-realm""#, Some(creds())).unwrap(),
+            RetryAuth::Basic(creds())
+        );
+        assert!(retry_auth(r#"Basic realm="basic-realm""#, None).is_err());
+    }
+
+    #[test]
+    fn bearer_challenge_requests_token_from_realm() {
+        let auth = retry_auth(
+            r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:a/b:pull""#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            auth,
+            RetryAuth::Token {
+                realm: "https://ghcr.io/token".into(),
+                query: vec![
+                    ("service".into(), "ghcr.io".into()),
+                    ("scope".into(), "repository:a/b:pull".into()),
+                ],
+                credentials: None,
+            }
+        );
+        assert!(retry_auth(r#"Bearer service="x""#, None).is_err());
+    }
+
+    #[test]
+    fn detects_docker_v2_manifests() {
+        let v2 = serde_json::json!({"schemaVersion": 2, "mediaType": DOCKER_V2_MANIFEST});
+        let list = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+            "manifests": []
+        });
+        let oci = serde_json::json!({"schemaVersion": 2, "config": {}});
+        assert!(is_docker_v2_manifest(&v2));
+        assert!(!is_docker_v2_manifest(&list));
+        assert!(!is_docker_v2_manifest(&oci));
+    }
+
+    #[test]
+    fn parses_image_references() {
+        assert_eq!(
+            ImageRef::parse("alpine").unwrap(),
+            ImageRef {
+                host: "docker.io".into(),
+                repository: "library/alpine".into(),
+                reference: "latest".into(),
+            }
+        );
+        let digest = format!("sha256:{}", "a".repeat(64));
+        assert_eq!(
+            ImageRef::parse(&format!("ghcr.io/org/app:1.0@{}", digest)).unwrap(),
+            ImageRef {
+                host: "ghcr.io".into(),
+                repository: "org/app".into(),
+                reference: digest,
+            }
+        );
+        assert_eq!(
+            ImageRef::parse("localhost:5000/app:dev").unwrap().host,
+            "localhost:5000"
+        );
+    }
+}
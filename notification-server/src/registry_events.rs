@@ -0,0 +1,170 @@
+//! Native Docker distribution notifications for `POST /registry-events`.
+//!
+//! The registry (see `docker-registry/config.yml`) posts an envelope of events
+//! (`application/vnd.docker.distribution.events.v1+json`) for every push and pull. We only
+//! care about tagged manifest pushes: blob uploads and pulls are noise, and the per-platform
+//! manifests of a multi-arch push arrive untagged ahead of the tagged index.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/// Manifest media types that represent a pushed image.
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.oci.image.index.v1+json",
+];
+
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    action: String,
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    target: Target,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Target {
+    #[serde(default)]
+    media_type: String,
+    #[serde(default)]
+    digest: String,
+    #[serde(default)]
+    repository: String,
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+/// A tagged manifest push pulled out of an envelope.
+#[derive(Debug, Clone)]
+pub struct ManifestPush {
+    pub event_id: String,
+    pub repository: String,
+    pub tag: String,
+    pub digest: String,
+    pub media_type: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Parse an envelope and keep its tagged manifest pushes, in order.
+///
+/// Returns the pushes plus how many events were skipped.
+pub fn manifest_pushes(body: &[u8]) -> Result<(Vec<ManifestPush>, usize), serde_json::Error> {
+    let envelope: Envelope = serde_json::from_slice(body)?;
+    let total = envelope.events.len();
+
+    let pushes: Vec<ManifestPush> = envelope
+        .events
+        .into_iter()
+        .filter(|e| e.action == "push")
+        .filter(|e| MANIFEST_MEDIA_TYPES.contains(&e.target.media_type.as_str()))
+        .filter_map(|e| {
+            let tag = e.target.tag.filter(|t| !t.is_empty())?;
+            if e.target.repository.is_empty() || !e.target.digest.starts_with("sha256:") {
+                return None;
+            }
+            Some(ManifestPush {
+                event_id: e.id,
+                repository: e.target.repository,
+                tag,
+                digest: e.target.digest,
+                media_type: e.target.media_type,
+                timestamp: e.timestamp.unwrap_or_else(Utc::now),
+            })
+        })
+        .collect();
+
+    let skipped = total - pushes.len();
+    Ok((pushes, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_tagged_manifest_pushes() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let body = serde_json::json!({
+            "events": [
+                {
+                    "id": "blob",
+                    "action": "push",
+                    "target": {
+                        "mediaType": "application/octet-stream",
+                        "digest": digest,
+                        "repository": "app"
+                    }
+                },
+                {
+                    "id": "untagged",
+                    "action": "push",
+                    "target": {
+                        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                        "digest": digest,
+                        "repository": "app"
+                    }
+                },
+                {
+                    "id": "pull",
+                    "action": "pull",
+                    "target": {
+                        "mediaType": "application/vnd.oci.image.index.v1+json",
+                        "digest": digest,
+                        "repository": "app",
+                        "tag": "v1"
+                    }
+                },
+                {
+                    "id": "tagged",
+                    "action": "push",
+                    "timestamp": "2026-01-02T03:04:05Z",
+                    "target": {
+                        "mediaType": "application/vnd.oci.image.index.v1+json",
+                        "digest": digest,
+                        "repository": "app",
+                        "tag": "v1"
+                    }
+                }
+            ]
+        });
+
+        let (pushes, skipped) = manifest_pushes(body.to_string().as_bytes()).unwrap();
+        assert_eq!(skipped, 3);
+        assert_eq!(pushes.len(), 1);
+        let push = &pushes[0];
+        assert_eq!(push.event_id, "tagged");
+        assert_eq!(push.repository, "app");
+        assert_eq!(push.tag, "v1");
+        assert_eq!(push.digest, digest);
+        assert_eq!(push.timestamp.to_rfc3339(), "2026-01-02T03:04:05+00:00");
+    }
+
+    #[test]
+    fn skips_pushes_without_a_sha256_digest() {
+        let body = br#"{"events": [{"action": "push", "target": {
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "digest": "md5:abc", "repository": "app", "tag": "latest"}}]}"#;
+        let (pushes, skipped) = manifest_pushes(body).unwrap();
+        assert!(pushes.is_empty());
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn rejects_invalid_envelopes() {
+        assert!(manifest_pushes(b"not json").is_err());
+        assert_eq!(manifest_pushes(b"{}").unwrap().1, 0);
+    }
+}
@@ -1,11 +1,17 @@
 //! Sprite deployment: create Fly.io Sprite, fetch squashfs from S3, run derived lane service.
 //!
-//! Triggered after lane export + Tigris upload. Returns the public lane RPC URL.
+//! Triggered after lane export + Tigris upload. Returns the public lane RPC URL once the
+//! lane answers `eth_chainId` with our chain ID.
+//! [`stop_sprite`] and [`destroy_sprite`] take a deployed lane down again.
 
 use sprites::{ServiceRequest, SpritesClient};
+use std::time::Duration;
 use tracing::{info, warn};
 
-use crate::tigris;
+use crate::{naming, tigris};
+
+/// Sprite service that runs the lane node.
+const LANE_SERVICE_NAME: &str = "lane-node";
 
 /// Result of deploying a Sprite for a lane build.
 #[derive(Debug, Clone)]
@@ -14,19 +20,39 @@ pub struct SpriteDeployResult {
     pub rpc_url: String,
 }
 
-/// Deploy a Sprite for the given digest. Assumes squashfs was already uploaded to
-/// s3://lane-exports/{digest}/vc-cm-snapshot-release.squashfs
+/// The Sprite was deployed but its lane RPC never answered. The service has been
+/// stopped; the Sprite itself is left for the caller to record.
+#[derive(Debug)]
+pub struct LaneRpcNotReady {
+    pub sprite_name: String,
+    pub rpc_url: String,
+    reason: String,
+}
+
+impl std::fmt::Display for LaneRpcNotReady {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for LaneRpcNotReady {}
+
+/// Deploy a Sprite for the given digest and lane profile. Assumes squashfs was already
+/// uploaded to the profile's export prefix (s3://lane-exports/{digest}/ for prod).
 ///
-/// Returns Ok with the RPC URL on success. Returns Err if Sprite deploy fails.
+/// Returns Ok with the RPC URL once it answers `eth_chainId` with `CHAIN_ID`. Returns Err
+/// if Sprite deploy fails or the RPC isn't ready within `LANE_RPC_READY_TIMEOUT_SECS`
+/// (then a [`LaneRpcNotReady`], with the lane service stopped).
 /// Sprite deploy is best-effort: build/export can succeed even if this fails.
 pub async fn deploy_sprite(
     digest: &str,
+    profile: &str,
 ) -> Result<SpriteDeployResult, Box<dyn std::error::Error + Send + Sync>> {
     let client = create_sprites_client().await?;
-    let sprite_name = sprite_name_from_digest(digest);
+    let sprite_name = sprite_name_from_digest(digest, profile).await?;
 
     // 1. Presigned URL for squashfs in S3
-    let squashfs_url = match tigris::presign_squashfs_get(digest, None) {
+    let squashfs_url = match tigris::presign_squashfs_get(digest, profile, None) {
         Ok(url) => url,
         Err(e) => {
             warn!("Could not generate presigned squashfs URL: {}", e);
@@ -66,8 +92,11 @@ pub async fn deploy_sprite(
     let http_port = lane_rpc_port();
     let request = lane_service_request(http_port);
 
-    create_service_put(&client, &sprite_name, "lane-node", &request).await?;
-    info!("Created service 'lane-node' on sprite {}", sprite_name);
+    create_service_put(&client, &sprite_name, LANE_SERVICE_NAME, &request).await?;
+    info!(
+        "Created service '{}' on sprite {}",
+        LANE_SERVICE_NAME, sprite_name
+    );
 
     // 4. Make sprite URL public
     update_url_settings_public(&client, &sprite_name).await?;
@@ -75,21 +104,212 @@ pub async fn deploy_sprite(
     // 5. Get sprite URL for response
     let rpc_url = get_sprite_url(&client, &sprite_name).await?;
 
+    // 6. Don't report the lane live until its RPC answers (Docker install, snapshot mount
+    // and derive-node startup all happen after the service PUT returns).
+    if let Err(e) = wait_for_lane_rpc(&sprite_name, &rpc_url).await {
+        if let Err(stop_err) = stop_sprite(&sprite_name).await {
+            warn!(
+                "Could not stop lane service on unready sprite {}: {}",
+                sprite_name, stop_err
+            );
+        }
+        return Err(Box::new(LaneRpcNotReady {
+            sprite_name,
+            rpc_url,
+            reason: e.to_string(),
+        }));
+    }
+
     Ok(SpriteDeployResult {
         sprite_name: sprite_name.clone(),
         rpc_url,
     })
 }
 
-fn sprite_name_from_digest(digest: &str) -> String {
-    // Sprites need alphanumeric + hyphen. Use last 12 chars of digest (after sha256:).
-    let short = digest
-        .trim_start_matches("sha256:")
+/// How long to wait for a freshly deployed lane RPC. Override with LANE_RPC_READY_TIMEOUT_SECS.
+fn lane_rpc_ready_timeout() -> Duration {
+    let secs = std::env::var("LANE_RPC_READY_TIMEOUT_SECS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(600);
+    Duration::from_secs(secs)
+}
+
+/// Pause between `eth_chainId` probes.
+const LANE_RPC_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Limit for a single probe.
+const LANE_RPC_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Poll `rpc_url` with `eth_chainId` until it returns [`lane_chain_id`] or the
+/// readiness deadline passes. The error carries the last probe's failure.
+async fn wait_for_lane_rpc(
+    sprite_name: &str,
+    rpc_url: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let expected = lane_chain_id();
+    let expected_id: u64 = expected
+        .trim()
+        .parse()
+        .map_err(|_| format!("CHAIN_ID {} is not a number", expected))?;
+    let timeout = lane_rpc_ready_timeout();
+    let deadline = tokio::time::Instant::now() + timeout;
+    let http = reqwest::Client::builder()
+        .timeout(LANE_RPC_PROBE_TIMEOUT)
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new());
+
+    info!(
+        "Waiting up to {}s for lane RPC {} to report chain {}",
+        timeout.as_secs(),
+        rpc_url,
+        expected_id
+    );
+    let mut attempts = 0u32;
+    loop {
+        attempts += 1;
+        let last_error = match probe_chain_id(&http, rpc_url).await {
+            Ok(id) if id == expected_id => {
+                info!(
+                    "Lane RPC on sprite {} is up (chain {}, {} probe(s))",
+                    sprite_name, id, attempts
+                );
+                return Ok(());
+            }
+            Ok(id) => format!("eth_chainId returned {} (expected {})", id, expected_id),
+            Err(e) => e.to_string(),
+        };
+        if tokio::time::Instant::now() + LANE_RPC_POLL_INTERVAL >= deadline {
+            return Err(format!(
+                "lane RPC {} on sprite {} not ready after {}s: {}",
+                rpc_url,
+                sprite_name,
+                timeout.as_secs(),
+                last_error
+            )
+            .into());
+        }
+        if attempts % 12 == 1 {
+            info!("Lane RPC {} not ready yet: {}", rpc_url, last_error);
+        }
+        tokio::time::sleep(LANE_RPC_POLL_INTERVAL).await;
+    }
+}
+
+/// Chain ID reported by the JSON-RPC at `rpc_url`.
+async fn probe_chain_id(
+    http: &reqwest::Client,
+    rpc_url: &str,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let body = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_chainId",
+        "params": []
+    });
+    let response = http.post(rpc_url).json(&body).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status().as_u16()).into());
+    }
+    let json: serde_json::Value = response.json().await?;
+    if let Some(err) = json.get("error") {
+        return Err(format!("JSON-RPC error: {}", err).into());
+    }
+    let result = json
+        .get("result")
+        .and_then(|v| v.as_str())
+        .ok_or("eth_chainId response has no result")?;
+    let hex = result.trim_start_matches("0x");
+    u64::from_str_radix(hex, 16).map_err(|_| format!("eth_chainId returned {}", result).into())
+}
+
+/// Stop the lane service on `sprite_name`. The sprite (and its squashfs) stays, so a
+/// later deploy of the same digest brings it back.
+pub async fn stop_sprite(
+    sprite_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = create_sprites_client().await?;
+    match client
+        .sprite(sprite_name)
+        .stop_service(LANE_SERVICE_NAME)
+        .await
+    {
+        Ok(()) => {}
+        Err(sprites::Error::NotFound(_)) => {
+            warn!(
+                "Service '{}' not found on sprite {}; treating it as stopped",
+                LANE_SERVICE_NAME, sprite_name
+            );
+        }
+        Err(e) => return Err(e.to_string().into()),
+    }
+    info!(
+        "Stopped service '{}' on sprite {}",
+        LANE_SERVICE_NAME, sprite_name
+    );
+    Ok(())
+}
+
+/// Delete `sprite_name`. A sprite that is already gone counts as destroyed.
+pub async fn destroy_sprite(
+    sprite_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = create_sprites_client().await?;
+    match client.delete(sprite_name).await {
+        Ok(()) => info!("Destroyed sprite {}", sprite_name),
+        Err(sprites::Error::NotFound(_)) => {
+            warn!("Sprite {} not found; treating it as destroyed", sprite_name)
+        }
+        Err(e) => return Err(e.to_string().into()),
+    }
+    Ok(())
+}
+
+/// What follows the digest-derived part of a sprite name: nothing for prod, otherwise
+/// `-<profile>` (Sprites need lowercase alphanumerics and hyphens).
+fn sprite_name_suffix(profile: &str) -> String {
+    if profile == "prod" {
+        return String::new();
+    }
+    let cleaned: String = profile
         .chars()
-        .filter(|c| c.is_ascii_alphanumeric())
-        .take(12)
-        .collect::<String>();
-    format!("lane-{}", if short.is_empty() { "default" } else { &short })
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("-{}", cleaned)
+}
+
+/// Sprite for `digest` in `profile`: the shared digest-derived name (see [`naming`]), with
+/// `-<profile>` appended for anything but prod.
+///
+/// A name recorded in the sprite index for another digest (or profile) is never reused
+/// unless that sprite was destroyed; a longer prefix of the digest is used instead.
+async fn sprite_name_from_digest(
+    digest: &str,
+    profile: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let suffix = &sprite_name_suffix(profile);
+    let base = naming::choose(digest, "sprite", |base| async move {
+        let name = format!("{}{}", base, suffix);
+        Ok(match tigris::sprite_index_record(&name).await? {
+            None => naming::Owner::Free,
+            Some(record) if record.status == tigris::SPRITE_DESTROYED => naming::Owner::Free,
+            Some(record) if record.digest == digest && record.profile == profile => {
+                naming::Owner::Same
+            }
+            Some(record) => naming::Owner::Other(format!("{} ({})", record.digest, record.profile)),
+        })
+    })
+    .await?;
+    Ok(format!("{}{}", base, suffix))
+}
+
+fn lane_chain_id() -> String {
+    std::env::var("CHAIN_ID").unwrap_or_else(|_| "1281453634".to_string())
 }
 
 fn lane_rpc_port() -> u16 {
@@ -103,7 +323,7 @@ fn lane_rpc_port() -> u16 {
 /// Uses derive-node (squashfs snapshot) anchored to lane-espresso RPC.
 /// DERIVED_DA_ADDRESS must be set (required by derive-node); CORE_RPC_URL defaults to lane-espresso.
 fn build_lane_compose() -> String {
-    let chain_id = std::env::var("CHAIN_ID").unwrap_or_else(|_| "1281453634".to_string());
+    let chain_id = lane_chain_id();
     let core_rpc_url = std::env::var("CORE_RPC_URL")
         .unwrap_or_else(|_| "https://lane-espresso.fly.dev/".to_string());
     let derived_da_address = std::env::var("DERIVED_DA_ADDRESS").unwrap_or_else(|_| String::new());
@@ -201,10 +421,33 @@ exec sudo docker compose -f /srv/docker-compose.yml up
     }
 }
 
+/// Per-request limit for Sprites API calls. Override with SPRITES_API_TIMEOUT_SECS.
+/// The whole deploy is additionally bounded by the sprite_deploy stage timeout.
+fn sprites_api_timeout() -> Duration {
+    let secs = std::env::var("SPRITES_API_TIMEOUT_SECS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(60);
+    Duration::from_secs(secs)
+}
+
+fn sprites_http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(sprites_api_timeout())
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new())
+}
+
+fn sprites_client_with_token(token: String) -> SpritesClient {
+    SpritesClient::builder(token)
+        .http_client(sprites_http_client())
+        .build()
+}
+
 async fn create_sprites_client() -> Result<SpritesClient, Box<dyn std::error::Error + Send + Sync>>
 {
     if let Ok(token) = std::env::var("SPRITES_TOKEN") {
-        return Ok(SpritesClient::new(token));
+        return Ok(sprites_client_with_token(token));
     }
 
     let fly_token = std::env::var("FLY_API_TOKEN")
@@ -213,10 +456,14 @@ async fn create_sprites_client() -> Result<SpritesClient, Box<dyn std::error::Er
         .or_else(|_| std::env::var("FLY_ORG"))
         .map_err(|_| "Set SPRITES_ORG or FLY_ORG when using FLY_API_TOKEN")?;
 
-    let token = SpritesClient::create_token(&fly_token, &org, None)
-        .await
-        .map_err(|e| format!("Sprites token exchange failed: {}", e))?;
-    Ok(SpritesClient::new(token))
+    let token = tokio::time::timeout(
+        sprites_api_timeout(),
+        SpritesClient::create_token(&fly_token, &org, None),
+    )
+    .await
+    .map_err(|_| "Sprites token exchange timed out")?
+    .map_err(|e| format!("Sprites token exchange failed: {}", e))?;
+    Ok(sprites_client_with_token(token))
 }
 
 /// Download squashfs from presigned URL into sprite at /data/vc-cm-snapshot.squashfs
@@ -273,7 +520,7 @@ async fn create_service_put(
         base, sprite_name, service_name
     );
 
-    let response = reqwest::Client::new()
+    let response = sprites_http_client()
         .put(&url)
         .header("Authorization", format!("Bearer {}", client.token()))
         .json(request)
@@ -300,7 +547,7 @@ async fn update_url_settings_public(
         "url_settings": { "auth": "public" }
     });
 
-    let response = reqwest::Client::new()
+    let response = sprites_http_client()
         .put(&url)
         .header("Authorization", format!("Bearer {}", client.token()))
         .json(&body)
@@ -328,7 +575,7 @@ async fn get_sprite_url(
     let base = client.base_url().trim_end_matches('/');
     let url = format!("{}/v1/sprites/{}", base, sprite_name);
 
-    let response = reqwest::Client::new()
+    let response = sprites_http_client()
         .get(&url)
         .header("Authorization", format!("Bearer {}", client.token()))
         .send()
@@ -347,3 +594,15 @@ async fn get_sprite_url(
 
     Ok(rpc_url)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprite_names_carry_non_prod_profiles() {
+        assert_eq!(sprite_name_suffix("prod"), "");
+        assert_eq!(sprite_name_suffix("staging"), "-staging");
+        assert_eq!(sprite_name_suffix("Dev_EU.1"), "-dev-eu-1");
+    }
+}
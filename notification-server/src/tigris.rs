@@ -1,12 +1,17 @@
 //! Tigris S3: upload exports and presigned URLs for squashfs
 
+use chrono::{DateTime, Utc};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use s3::creds::Credentials;
+use s3::error::S3Error;
 use s3::{Bucket, Region};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use tracing::{info, warn};
 use walkdir::WalkDir;
 
+use crate::multipart;
+
 const BUCKET_NAME: &str = "lane-exports";
 const REGION: &str = "ap-northeast-2";
 const ENDPOINT: &str = "https://t3.storage.dev";
@@ -17,6 +22,83 @@ pub fn squashfs_filename() -> String {
     std::env::var("SQUASHFS_FILENAME").unwrap_or_else(|_| "vc-cm-snapshot.squashfs".to_string())
 }
 
+/// Written last under an export's prefix; its presence means the export is complete.
+pub const EXPORT_MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Written first under an export's prefix and removed once the manifest is in place, so a
+/// partial upload can be told apart from an export made before manifests existed.
+const EXPORT_PENDING_FILENAME: &str = ".export-pending";
+
+/// Profile whose exports live directly under `{digest}/`, where they were before
+/// exports were split by profile.
+const ROOT_PROFILE: &str = "prod";
+
+/// S3 prefix (without trailing slash) for a digest's export in `profile`:
+/// `{digest}` for prod, `{profile}/{digest}` for anything else. Profiles can't contain
+/// `:`, so their prefixes never overlap a digest's (or anything an export nests in it).
+pub fn export_prefix(digest: &str, profile: &str) -> String {
+    if profile == ROOT_PROFILE {
+        digest.to_string()
+    } else {
+        format!("{}/{}", profile, digest)
+    }
+}
+
+/// Which exported files get published, from `LANE_EXPORT_INCLUDE` and
+/// `LANE_EXPORT_EXCLUDE` (comma-separated globs over paths relative to the export
+/// directory; `*` stays within one directory, `**` crosses them). With no include
+/// patterns everything is included; excludes win over includes.
+struct ExportFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl ExportFilter {
+    fn from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let include = glob_set("LANE_EXPORT_INCLUDE")?;
+        let exclude = glob_set("LANE_EXPORT_EXCLUDE")?.unwrap_or_else(GlobSet::empty);
+        Ok(Self { include, exclude })
+    }
+
+    fn publishes(&self, relative_path: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|set| set.is_match(relative_path))
+            && !self.exclude.is_match(relative_path)
+    }
+}
+
+fn glob_set(var: &str) -> Result<Option<GlobSet>, Box<dyn std::error::Error + Send + Sync>> {
+    let raw = std::env::var(var).unwrap_or_default();
+    let patterns: Vec<&str> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("invalid pattern in {}: {}", var, e))?;
+        builder.add(glob);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// `path` relative to `root`, with `/` separators (as used in S3 keys and the manifest).
+fn relative_key_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect();
+    Some(parts?.join("/"))
+}
+
 fn tigris_credentials() -> Result<Credentials, String> {
     let access_key = std::env::var("AWS_ACCESS_KEY_ID")
         .or_else(|_| std::env::var("TIGRIS_ACCESS_KEY_ID"))
@@ -61,6 +143,24 @@ fn sprite_index_key(chain_id: &str) -> String {
     )
 }
 
+/// Prefix of the chain's sprite history: one object per index change.
+fn sprite_history_prefix(chain_id: &str) -> String {
+    format!(
+        "{}/{}/history/",
+        sprite_index_prefix().trim_end_matches('/'),
+        chain_id
+    )
+}
+
+/// Every history key starts with this minus the event's microseconds since the epoch,
+/// zero-padded to 17 digits, so an ascending listing returns the newest events first.
+const HISTORY_KEY_TIME_MAX: i64 = 99_999_999_999_999_999;
+
+/// The reverse timestamp that starts history keys for events at `at`.
+fn history_key_time(at: DateTime<Utc>) -> String {
+    format!("{:017}", HISTORY_KEY_TIME_MAX - at.timestamp_micros())
+}
+
 fn sprite_index_bucket() -> Result<Bucket, String> {
     let credentials = tigris_credentials()?;
     let region = Region::Custom {
@@ -70,6 +170,17 @@ fn sprite_index_bucket() -> Result<Bucket, String> {
     Bucket::new(&sprite_index_bucket_name(), region, credentials).map_err(|e| e.to_string())
 }
 
+/// `SpriteIndexRecord::status` of a sprite serving its lane RPC.
+pub const SPRITE_ACTIVE: &str = "active";
+/// The sprite exists but its lane service was stopped.
+pub const SPRITE_STOPPED: &str = "stopped";
+/// The sprite was deleted; its name may be reused (see [`naming`](crate::naming)).
+pub const SPRITE_DESTROYED: &str = "destroyed";
+
+fn default_profile() -> String {
+    ROOT_PROFILE.to_string()
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SpriteIndexRecord {
     pub sprite_name: String,
@@ -77,6 +188,10 @@ pub struct SpriteIndexRecord {
     pub do_poll_url: String,
     pub status: String,
     pub digest: String,
+    /// Lane profile the sprite's squashfs was built with (records written before
+    /// profiles were tracked are prod).
+    #[serde(default = "default_profile")]
+    pub profile: String,
     pub last_changed_at: String,
 }
 
@@ -88,227 +203,952 @@ pub struct ActiveSpritesIndex {
     pub sprites: Vec<SpriteIndexRecord>,
 }
 
-/// Upload all files from export_dir to s3://lane-exports/{digest}/
+/// One change to a sprite's index record. Stored as its own object under the chain's
+/// history prefix and never rewritten.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpriteHistoryEvent {
+    pub at: String,
+    pub sprite_name: String,
+    pub digest: String,
+    pub profile: String,
+    pub rpc_url: String,
+    /// Digest the record held before the change (`None` for a new record).
+    pub previous_digest: Option<String>,
+    /// `None` for a new record.
+    pub old_status: Option<String>,
+    pub new_status: String,
+    /// Job that made the change; `None` for changes made through the admin API.
+    pub job_id: Option<String>,
+}
+
+/// Optional filters for [`sprite_history`]; unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct SpriteHistoryFilter {
+    pub sprite_name: Option<String>,
+    pub digest: Option<String>,
+    pub job_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only events older than this cursor (a previous page's `next_cursor`).
+    pub before: Option<String>,
+    pub limit: usize,
+}
+
+/// Index of a complete export, uploaded as `{prefix}/manifest.json` after every file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub version: u8,
+    pub digest: String,
+    pub profile: String,
+    pub lane_cli_version: String,
+    /// When `lane export` wrote the files (newest file modification time).
+    pub built_at: String,
+    pub uploaded_at: String,
+    pub files: Vec<ExportManifestFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportManifestFile {
+    /// Path relative to the export prefix.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: String,
+}
+
+/// Files an export must contain to be uploaded at all: the squashfs, plus anything listed
+/// in `LANE_REQUIRED_ARTIFACTS` (comma-separated, relative to the export directory).
+fn required_artifacts() -> Vec<String> {
+    let mut required = vec![squashfs_filename()];
+    for extra in std::env::var("LANE_REQUIRED_ARTIFACTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        if !required.iter().any(|r| r == extra) {
+            required.push(extra.to_string());
+        }
+    }
+    required
+}
+
+fn upload_file_retries() -> u32 {
+    std::env::var("LANE_UPLOAD_FILE_RETRIES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(3)
+}
+
+/// An export that did not make it to Tigris in full. No manifest was written for it.
+#[derive(Debug)]
+pub struct UploadIncomplete {
+    /// Keys that did upload.
+    pub uploaded_keys: Vec<String>,
+    /// Required files `lane export` did not produce.
+    pub missing: Vec<String>,
+    /// Files that still failed after every retry, with the last error.
+    pub failed: Vec<(String, String)>,
+}
+
+impl UploadIncomplete {
+    /// Every file that is not in Tigris: missing ones first, then failed uploads.
+    pub fn failed_files(&self) -> Vec<String> {
+        self.missing
+            .iter()
+            .cloned()
+            .chain(self.failed.iter().map(|(file, _)| file.clone()))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for UploadIncomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "export upload incomplete")?;
+        if !self.missing.is_empty() {
+            write!(f, "; missing required file(s): {}", self.missing.join(", "))?;
+        }
+        for (file, error) in &self.failed {
+            write!(f, "; failed to upload {}: {}", file, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UploadIncomplete {}
+
+/// Content type to store an exported file with, from its extension.
+fn content_type_for(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("json") => "application/json",
+        Some("txt") | Some("log") => "text/plain; charset=utf-8",
+        Some("tar") => "application/x-tar",
+        Some("gz") | Some("tgz") => "application/gzip",
+        Some("zst") => "application/zstd",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Upload the export_dir tree to s3://lane-exports/{digest}/ (or
+/// s3://lane-exports/{profile}/{digest}/ for profiles other than prod), keeping paths
+/// relative to export_dir, then `manifest.json` if every file made it. Files the
+/// [`ExportFilter`] doesn't publish are skipped; required files are always uploaded.
+/// Large files stream up in parts (see [`multipart`](crate::multipart)), keeping
+/// resumable progress in `upload_state_dir`; a file that fails is retried
+/// `LANE_UPLOAD_FILE_RETRIES` times (default 3) with backoff.
+///
+/// Returns the S3 keys that were uploaded. Fails with [`UploadIncomplete`] if a required
+/// file is missing (before uploading anything) or any file could not be uploaded.
 pub async fn upload_to_tigris(
     digest: &str,
+    profile: &str,
     export_dir: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    upload_state_dir: &Path,
+    lane_cli_version: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
     let bucket = bucket()?;
     let export_path = Path::new(export_dir);
     if !export_path.exists() {
         return Err(format!("Export directory '{}' does not exist", export_dir).into());
     }
 
-    let mut uploaded_count = 0;
-    let mut error_count = 0;
+    let filter = ExportFilter::from_env()?;
+    let required = required_artifacts();
+    let missing: Vec<String> = required
+        .iter()
+        .filter(|file| !export_path.join(file).is_file())
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        return Err(UploadIncomplete {
+            uploaded_keys: Vec::new(),
+            missing,
+            failed: Vec::new(),
+        }
+        .into());
+    }
+
+    let prefix = export_prefix(digest, profile);
+    let pending_key = format!("{}/{}", prefix, EXPORT_PENDING_FILENAME);
+    bucket.put_object(&pending_key, b"").await?;
+
+    let retries = upload_file_retries();
+    let mut uploaded_keys = Vec::new();
+    let mut manifest_files = Vec::new();
+    let mut built_at: Option<DateTime<Utc>> = None;
+    let mut failed = Vec::new();
+    let mut skipped = 0;
 
     for entry in WalkDir::new(export_path)
         .min_depth(1)
-        .max_depth(1)
+        .sort_by_file_name()
         .into_iter()
         .filter_map(|e| e.ok())
     {
         let path = entry.path();
 
         if path.is_file() {
-            let filename = path
-                .file_name()
-                .and_then(|n| n.to_str())
-                .ok_or("Invalid filename")?;
+            let relative = relative_key_path(export_path, path)
+                .ok_or_else(|| format!("Invalid path in export: {}", path.display()))?;
+            if !required.contains(&relative) && !filter.publishes(&relative) {
+                info!("Not publishing {} (excluded by export filter)", relative);
+                skipped += 1;
+                continue;
+            }
+            if relative == EXPORT_MANIFEST_FILENAME || relative == EXPORT_PENDING_FILENAME {
+                warn!(
+                    "Skipping exported {}: the name is reserved for the export bookkeeping",
+                    relative
+                );
+                continue;
+            }
 
-            let s3_key = format!("{}/{}", digest, filename);
+            let s3_key = format!("{}/{}", prefix, relative);
+            let content_type = content_type_for(path);
 
-            info!("Uploading {} to s3://lane-exports/{}", filename, s3_key);
+            info!("Uploading {} to s3://lane-exports/{}", relative, s3_key);
 
-            match upload_file(&bucket, path, &s3_key).await {
-                Ok(_) => {
-                    info!("Successfully uploaded {}", filename);
-                    uploaded_count += 1;
+            let mut attempt = 0;
+            let result = loop {
+                match multipart::upload_file(&bucket, path, &s3_key, content_type, upload_state_dir)
+                    .await
+                {
+                    Err(e) if attempt < retries => {
+                        attempt += 1;
+                        let delay = std::time::Duration::from_secs(2 << attempt.min(5));
+                        warn!(
+                            "Failed to upload {} ({}); retry {}/{} in {:?}",
+                            relative, e, attempt, retries, delay
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    result => break result,
+                }
+            };
+            match result {
+                Ok((size, sha256)) => {
+                    info!("Successfully uploaded {}", relative);
+                    if let Ok(modified) = path.metadata().and_then(|m| m.modified()) {
+                        let modified = DateTime::<Utc>::from(modified);
+                        built_at = Some(built_at.map_or(modified, |b| b.max(modified)));
+                    }
+                    uploaded_keys.push(s3_key);
+                    manifest_files.push(ExportManifestFile {
+                        path: relative.clone(),
+                        size,
+                        sha256,
+                        content_type: content_type.to_string(),
+                    });
                 }
                 Err(e) => {
-                    warn!("Failed to upload {}: {}", filename, e);
-                    error_count += 1;
+                    warn!("Failed to upload {}: {}", relative, e);
+                    failed.push((relative.clone(), e.to_string()));
                 }
             }
         }
     }
 
     info!(
-        "Upload complete! Successfully uploaded: {} files to s3://lane-exports/{}",
-        uploaded_count, digest
+        "Upload complete! Successfully uploaded: {} files to s3://lane-exports/{} ({} not published)",
+        uploaded_keys.len(),
+        prefix,
+        skipped
     );
-    if error_count > 0 {
-        warn!("Failed to upload: {} files", error_count);
+    if !failed.is_empty() {
+        warn!("Failed to upload: {} files", failed.len());
+        warn!(
+            "Not writing {} for s3://lane-exports/{}; the export is incomplete",
+            EXPORT_MANIFEST_FILENAME, prefix
+        );
+        return Err(UploadIncomplete {
+            uploaded_keys,
+            missing: Vec::new(),
+            failed,
+        }
+        .into());
     }
 
-    Ok(())
-}
-
-async fn upload_file(
-    bucket: &Bucket,
-    file_path: &Path,
-    s3_key: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let metadata = tokio::fs::metadata(file_path)
-        .await
-        .map_err(|e| format!("Failed to get metadata for file: {:?}: {}", file_path, e))?;
-    let file_size = metadata.len();
-
+    let now = Utc::now();
+    let manifest = ExportManifest {
+        version: 1,
+        digest: digest.to_string(),
+        profile: profile.to_string(),
+        lane_cli_version: lane_cli_version.to_string(),
+        built_at: built_at.unwrap_or(now).to_rfc3339(),
+        uploaded_at: now.to_rfc3339(),
+        files: manifest_files,
+    };
+    let manifest_key = format!("{}/{}", prefix, EXPORT_MANIFEST_FILENAME);
+    let payload = serde_json::to_vec_pretty(&manifest)?;
+    let response = bucket
+        .put_object_with_content_type(&manifest_key, &payload, "application/json")
+        .await?;
+    if response.status_code() != 200 {
+        return Err(format!(
+            "failed writing export manifest {}, status {}",
+            manifest_key,
+            response.status_code()
+        )
+        .into());
+    }
     info!(
-        "Uploading file: {} (size: {} bytes)",
-        file_path.display(),
-        file_size
+        "Wrote export manifest s3://lane-exports/{} ({} files)",
+        manifest_key,
+        manifest.files.len()
     );
+    uploaded_keys.push(manifest_key);
 
-    if file_size > 5 * 1024 * 1024 {
-        info!(
-            "Using multipart upload for large file: {}",
-            file_path.display()
-        );
+    // Only matters while there is no manifest, so a leftover marker is harmless.
+    if let Err(e) = bucket.delete_object(&pending_key).await {
+        warn!("Could not remove s3://lane-exports/{}: {}", pending_key, e);
     }
 
-    let content = tokio::fs::read(file_path)
-        .await
-        .map_err(|e| format!("Failed to read file: {:?}: {}", file_path, e))?;
+    Ok(uploaded_keys)
+}
+
+/// If a previous job already uploaded an export for this digest and profile, return its
+/// S3 keys.
+///
+/// The export counts as present once its `manifest.json` is there (it is written last);
+/// the keys are then the manifest's files plus the manifest itself. A prefix without a
+/// manifest still counts if it holds the squashfs and no pending marker, since exports
+/// uploaded before manifests existed look like that; anything else is a partial upload
+/// and gets rebuilt.
+pub async fn existing_export_keys(
+    digest: &str,
+    profile: &str,
+) -> Result<Option<Vec<String>>, Box<dyn std::error::Error + Send + Sync>> {
+    let bucket = bucket()?;
+    let prefix = export_prefix(digest, profile);
+    let manifest_key = format!("{}/{}", prefix, EXPORT_MANIFEST_FILENAME);
 
-    let response = bucket.put_object(s3_key, &content).await.map_err(|e| {
-        format!(
-            "Failed to upload to s3://{}/{}: {}",
-            bucket.name(),
-            s3_key,
-            e
-        )
-    })?;
+    let manifest: ExportManifest = match bucket.get_object(&manifest_key).await {
+        Ok(response) => serde_json::from_slice(response.bytes())
+            .map_err(|e| format!("unreadable export manifest {}: {}", manifest_key, e))?,
+        Err(e) if is_http_status(&e, &[404]) => return legacy_export_keys(&bucket, &prefix).await,
+        Err(e) => return Err(e.into()),
+    };
 
-    if response.status_code() == 200 {
-        Ok(())
-    } else {
-        Err(format!("Upload failed with status code: {}", response.status_code()).into())
+    let mut keys: Vec<String> = manifest
+        .files
+        .iter()
+        .map(|file| format!("{}/{}", prefix, file.path))
+        .collect();
+    keys.push(manifest_key);
+    Ok(Some(keys))
+}
+
+/// Keys of an export uploaded before manifests were written, if `prefix` holds one.
+async fn legacy_export_keys(
+    bucket: &Bucket,
+    prefix: &str,
+) -> Result<Option<Vec<String>>, Box<dyn std::error::Error + Send + Sync>> {
+    let keys: Vec<String> = bucket
+        .list(format!("{}/", prefix), None)
+        .await?
+        .into_iter()
+        .flat_map(|page| page.contents)
+        .map(|object| object.key)
+        .collect();
+
+    let pending_key = format!("{}/{}", prefix, EXPORT_PENDING_FILENAME);
+    let squashfs_key = format!("{}/{}", prefix, squashfs_filename());
+    if keys.contains(&pending_key) || !keys.contains(&squashfs_key) {
+        return Ok(None);
     }
+    info!(
+        "Export s3://lane-exports/{}/ predates {}; reusing its {} files",
+        prefix,
+        EXPORT_MANIFEST_FILENAME,
+        keys.len()
+    );
+    Ok(Some(keys))
 }
 
-/// Generate a presigned GET URL for the squashfs at s3://lane-exports/{digest}/{filename}.
-/// Expires in 1 hour. Caller can pass custom filename or use default (vc-cm-snapshot.squashfs, overridable via SQUASHFS_FILENAME env).
+/// Generate a presigned GET URL for the squashfs in the export of {digest} for {profile}
+/// (see [`export_prefix`]). Expires in 1 hour. Caller can pass a custom filename or use
+/// the default (vc-cm-snapshot.squashfs, overridable via SQUASHFS_FILENAME env).
 pub fn presign_squashfs_get(
     digest: &str,
+    profile: &str,
     filename: Option<&str>,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let bucket = bucket()?;
     let filename = filename.map(String::from).unwrap_or_else(squashfs_filename);
-    let s3_key = format!("{}/{}", digest, filename);
+    let s3_key = format!("{}/{}", export_prefix(digest, profile), filename);
     let presigned = bucket.presign_get(&s3_key, 3600, None)?;
     Ok(presigned)
 }
 
-/// Upsert a sprite as active in chain-scoped index:
-/// s3://{SPRITE_INDEX_BUCKET}/{SPRITE_INDEX_PREFIX}/{CHAIN_ID}/active_sprites.json
-pub async fn upsert_active_sprite(
-    sprite_name: &str,
-    rpc_url: &str,
-    digest: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// Times a sprite index update is attempted when other writers keep getting in first.
+const SPRITE_INDEX_ATTEMPTS: u32 = 8;
+
+fn empty_sprite_index(chain_id: &str) -> ActiveSpritesIndex {
+    ActiveSpritesIndex {
+        version: 1,
+        chain_id: chain_id.to_string(),
+        updated_at: chrono::Utc::now().to_rfc3339(),
+        sprites: vec![],
+    }
+}
+
+/// The sprite index as read, with what a conditional write back needs.
+struct LoadedSpriteIndex {
+    index: ActiveSpritesIndex,
+    /// ETag of the object that was read; `None` if there is no index yet.
+    etag: Option<String>,
+}
+
+fn is_http_status(e: &S3Error, codes: &[u16]) -> bool {
+    matches!(e, S3Error::Http(code, _) if codes.contains(code))
+}
+
+/// `bucket`, sending `name: value` with every request.
+fn with_header(bucket: &Bucket, name: &str, value: &str) -> Bucket {
+    let mut bucket = bucket.clone();
+    bucket.add_header(name, value);
+    bucket
+}
+
+/// Read the chain's sprite index (an empty one if it doesn't exist yet) and its ETag.
+///
+/// The body is fetched with `If-Match` on the ETag from a HEAD, so the two always belong
+/// together; if the index is replaced in between, the read starts over.
+///
+/// An index that can't be parsed is an error rather than an empty index: nothing can say
+/// which sprites or names are in use until it is fixed or removed, and writing over it
+/// would lose them for good.
+async fn load_sprite_index(
+    bucket: &Bucket,
+    key: &str,
+    chain_id: &str,
+) -> Result<LoadedSpriteIndex, Box<dyn std::error::Error + Send + Sync>> {
+    let missing = || LoadedSpriteIndex {
+        index: empty_sprite_index(chain_id),
+        etag: None,
+    };
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let etag = match bucket.head_object(key).await {
+            Ok((_, 404)) => return Ok(missing()),
+            Ok((head, _)) => head
+                .e_tag
+                .ok_or_else(|| format!("sprite index {} has no ETag", key))?,
+            Err(e) if is_http_status(&e, &[404]) => return Ok(missing()),
+            Err(e) => return Err(format!("failed reading sprite index {}: {}", key, e).into()),
+        };
+
+        let response = match with_header(bucket, "If-Match", &etag).get_object(key).await {
+            Ok(response) => response,
+            // Replaced (412) or deleted (404) since the HEAD.
+            Err(e) if is_http_status(&e, &[404, 412]) && attempt < SPRITE_INDEX_ATTEMPTS => {
+                continue
+            }
+            Err(e) => return Err(format!("failed reading sprite index {}: {}", key, e).into()),
+        };
+
+        return match serde_json::from_slice::<ActiveSpritesIndex>(response.bytes()) {
+            Ok(index) => Ok(LoadedSpriteIndex {
+                index,
+                etag: Some(etag),
+            }),
+            Err(e) => Err(format!(
+                "sprite index s3://{}/{} is not valid JSON ({}); fix or remove it",
+                bucket.name(),
+                key,
+                e
+            )
+            .into()),
+        };
+    }
+}
+
+/// Write the index back if it is still the version that was read (`If-Match` its ETag, or
+/// `If-None-Match: *` if there was none). Returns false if another writer got there first.
+async fn save_sprite_index(
+    bucket: &Bucket,
+    key: &str,
+    index: &ActiveSpritesIndex,
+    etag: Option<&str>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = serde_json::to_vec_pretty(index)?;
+    let conditional = match etag {
+        Some(etag) => with_header(bucket, "If-Match", etag),
+        None => with_header(bucket, "If-None-Match", "*"),
+    };
+    match conditional.put_object(key, &payload).await {
+        Ok(response) if response.status_code() == 200 => Ok(true),
+        Ok(response) if matches!(response.status_code(), 409 | 412) => Ok(false),
+        Ok(response) => Err(format!(
+            "failed writing sprite index {}, status {}",
+            key,
+            response.status_code()
+        )
+        .into()),
+        // 412: the index changed since it was read; 409: a concurrent conditional write.
+        Err(e) if is_http_status(&e, &[409, 412]) => Ok(false),
+        Err(e) => Err(format!("failed writing sprite index {}: {}", key, e).into()),
+    }
+}
+
+/// Read-modify-write this chain's sprite index without losing concurrent updates.
+///
+/// `update` runs against a fresh read on every attempt and returns `None` if there is
+/// nothing to write. The write is conditional on the ETag that was read; when another
+/// writer got in between, the whole cycle is retried with backoff. Every record the
+/// write changed is then appended to the sprite history, attributed to `job_id`.
+///
+/// The history is best-effort: it is written after the index (so a write that loses the
+/// race never shows up in it), and an event that fails to store is only logged.
+async fn update_sprite_index<T, F>(
+    job_id: Option<&str>,
+    mut update: F,
+) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: FnMut(&mut ActiveSpritesIndex) -> Option<T>,
+{
     let bucket = sprite_index_bucket()?;
     let chain_id = sprite_chain_id();
     let key = sprite_index_key(&chain_id);
-    let now = chrono::Utc::now().to_rfc3339();
 
-    let existing = bucket.get_object(&key).await;
-    let mut index = match existing {
-        Ok(response) if response.status_code() == 200 => {
-            match serde_json::from_slice::<ActiveSpritesIndex>(response.bytes()) {
-                Ok(parsed) => parsed,
-                Err(e) => {
-                    warn!(
-                        "Failed parsing existing sprite index JSON at {} (reinitializing): {}",
-                        key, e
-                    );
-                    ActiveSpritesIndex {
-                        version: 1,
-                        chain_id: chain_id.clone(),
-                        updated_at: now.clone(),
-                        sprites: vec![],
-                    }
-                }
-            }
+    for attempt in 1..=SPRITE_INDEX_ATTEMPTS {
+        let mut loaded = load_sprite_index(&bucket, &key, &chain_id).await?;
+        let before = loaded.index.sprites.clone();
+        let Some(result) = update(&mut loaded.index) else {
+            return Ok(None);
+        };
+
+        if loaded.index.chain_id != chain_id {
+            loaded.index.chain_id = chain_id.clone();
+        }
+        if loaded.index.version == 0 {
+            loaded.index.version = 1;
         }
-        Ok(response) if response.status_code() == 404 => ActiveSpritesIndex {
-            version: 1,
-            chain_id: chain_id.clone(),
-            updated_at: now.clone(),
-            sprites: vec![],
-        },
-        Ok(response) => {
-            return Err(format!(
-                "failed reading sprite index {}, status {}",
+        loaded.index.updated_at = chrono::Utc::now().to_rfc3339();
+
+        if save_sprite_index(&bucket, &key, &loaded.index, loaded.etag.as_deref()).await? {
+            let events = history_events(&before, &loaded.index.sprites, job_id);
+            append_sprite_history(&bucket, &chain_id, &events).await;
+            return Ok(Some(result));
+        }
+
+        if attempt < SPRITE_INDEX_ATTEMPTS {
+            // Jitter so writers that collided don't collide again.
+            let jitter = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.subsec_millis() % 100)
+                .unwrap_or(0);
+            let delay = std::time::Duration::from_millis((100 << attempt.min(5)) + jitter as u64);
+            warn!(
+                "Sprite index {} changed while updating it; retry {}/{} in {:?}",
                 key,
-                response.status_code()
-            )
-            .into());
+                attempt,
+                SPRITE_INDEX_ATTEMPTS - 1,
+                delay
+            );
+            tokio::time::sleep(delay).await;
         }
-        Err(e) if e.to_string().contains("HTTP 404") || e.to_string().contains("NoSuchKey") => {
-            ActiveSpritesIndex {
-                version: 1,
-                chain_id: chain_id.clone(),
-                updated_at: now.clone(),
-                sprites: vec![],
+    }
+    Err(format!(
+        "sprite index {} kept changing; gave up after {} attempts",
+        key, SPRITE_INDEX_ATTEMPTS
+    )
+    .into())
+}
+
+/// Events for the records an update changed. Records are only ever modified in place or
+/// appended, so `before[i]` is what `after[i]` used to be.
+fn history_events(
+    before: &[SpriteIndexRecord],
+    after: &[SpriteIndexRecord],
+    job_id: Option<&str>,
+) -> Vec<SpriteHistoryEvent> {
+    after
+        .iter()
+        .enumerate()
+        .filter_map(|(i, record)| {
+            let old = before.get(i);
+            if old.is_some_and(|old| old.last_changed_at == record.last_changed_at) {
+                return None;
             }
-        }
-        Err(e) => return Err(e.into()),
-    };
+            Some(SpriteHistoryEvent {
+                at: record.last_changed_at.clone(),
+                sprite_name: record.sprite_name.clone(),
+                digest: record.digest.clone(),
+                profile: record.profile.clone(),
+                rpc_url: record.rpc_url.clone(),
+                previous_digest: old.map(|old| old.digest.clone()),
+                old_status: old.map(|old| old.status.clone()),
+                new_status: record.status.clone(),
+                job_id: job_id.map(String::from),
+            })
+        })
+        .collect()
+}
 
-    if index.chain_id != chain_id {
-        index.chain_id = chain_id.clone();
+/// Write each event as a new object under the chain's history prefix, keyed
+/// `{reverse timestamp}-{sprite_name}-{id}.json` so keys list newest first.
+///
+/// The index itself is already updated at this point, so failures are only logged.
+async fn append_sprite_history(bucket: &Bucket, chain_id: &str, events: &[SpriteHistoryEvent]) {
+    let prefix = sprite_history_prefix(chain_id);
+    for event in events {
+        let at = DateTime::parse_from_rfc3339(&event.at)
+            .map(|at| at.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        let key = format!(
+            "{}{}-{}-{}.json",
+            prefix,
+            history_key_time(at),
+            event.sprite_name,
+            &uuid::Uuid::new_v4().simple().to_string()[..8]
+        );
+        let result = match serde_json::to_vec_pretty(event) {
+            Ok(payload) => bucket
+                .put_object(&key, &payload)
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|response| match response.status_code() {
+                    200 => Ok(()),
+                    status => Err(format!("status {}", status)),
+                }),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = result {
+            warn!(
+                "⚠️ Failed recording sprite history event for {} at {}: {}",
+                event.sprite_name, key, e
+            );
+        }
     }
+}
 
-    if index.version == 0 {
-        index.version = 1;
+/// `(reverse timestamp, sprite name)` from a history object name (the key without the
+/// history prefix), which is `{reverse timestamp}-{sprite_name}-{id}.json`.
+fn parse_history_name(name: &str) -> Option<(&str, &str)> {
+    let (at, rest) = name.strip_suffix(".json")?.split_once('-')?;
+    if at.len() != 17 || !at.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    let (sprite_name, _id) = rest.rsplit_once('-')?;
+    Some((at, sprite_name))
+}
 
-    let do_poll_url = format!("{}/do_poll", rpc_url.trim_end_matches('/'));
-    let mut found = false;
-    for record in &mut index.sprites {
-        if record.sprite_name == sprite_name
-            || record.rpc_url == rpc_url
-            || record.do_poll_url == do_poll_url
-        {
-            record.sprite_name = sprite_name.to_string();
-            record.rpc_url = rpc_url.to_string();
-            record.do_poll_url = do_poll_url.clone();
-            record.status = "active".to_string();
-            record.digest = digest.to_string();
-            record.last_changed_at = now.clone();
-            found = true;
+/// History objects fetched at once by [`sprite_history`].
+const HISTORY_FETCH_CONCURRENCY: usize = 8;
+/// Keys requested per listing call by [`sprite_history`].
+const HISTORY_LIST_PAGE_SIZE: usize = 100;
+
+/// One page of [`sprite_history`].
+pub struct SpriteHistoryPage {
+    pub events: Vec<SpriteHistoryEvent>,
+    /// Pass back as [`SpriteHistoryFilter::before`] for the next, older page; `None` once
+    /// there is nothing older.
+    pub next_cursor: Option<String>,
+}
+
+/// A page of this chain's sprite history, newest first.
+///
+/// Keys sort newest first, so the listing starts after the cursor (or at `until`) and
+/// stops at the first key older than `since`. Sprite name filters are applied to the
+/// keys; digest and job filters need each event read, so they are applied while
+/// reading, a few objects at a time. Listing stops as soon as `limit` events match.
+pub async fn sprite_history(
+    filter: &SpriteHistoryFilter,
+) -> Result<SpriteHistoryPage, Box<dyn std::error::Error + Send + Sync>> {
+    let bucket = sprite_index_bucket()?;
+    let prefix = sprite_history_prefix(&sprite_chain_id());
+    let since = filter.since.map(history_key_time);
+    // Any key starting with the reverse `until` (or a cursor) sorts after it.
+    let start_after = [filter.until.map(history_key_time), filter.before.clone()]
+        .into_iter()
+        .flatten()
+        .max()
+        .map(|name| format!("{}{}", prefix, name));
+
+    let mut events = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let (page, _) = bucket
+            .list_page(
+                prefix.clone(),
+                None,
+                continuation_token,
+                start_after.clone(),
+                Some(HISTORY_LIST_PAGE_SIZE),
+            )
+            .await?;
+        let mut past_since = false;
+        let mut names = Vec::new();
+        for object in &page.contents {
+            let Some(name) = object.key.strip_prefix(&prefix) else {
+                continue;
+            };
+            let Some((at, sprite_name)) = parse_history_name(name) else {
+                continue;
+            };
+            if since.as_deref().is_some_and(|since| at > since) {
+                past_since = true;
+                break;
+            }
+            if filter
+                .sprite_name
+                .as_deref()
+                .is_none_or(|wanted| sprite_name == wanted)
+            {
+                names.push(name.to_string());
+            }
+        }
+        continuation_token = page.next_continuation_token.filter(|_| !past_since);
+        let more_listed = continuation_token.is_some();
+
+        for (chunk_index, chunk) in names.chunks(HISTORY_FETCH_CONCURRENCY).enumerate() {
+            let mut fetches = tokio::task::JoinSet::new();
+            for (i, name) in chunk.iter().enumerate() {
+                let bucket = bucket.clone();
+                let key = format!("{}{}", prefix, name);
+                fetches.spawn(async move { (i, bucket.get_object(&key).await) });
+            }
+            let mut bodies = vec![Vec::new(); chunk.len()];
+            while let Some(fetched) = fetches.join_next().await {
+                let (i, response) = fetched?;
+                bodies[i] = response?.bytes().to_vec();
+            }
+
+            for (i, (name, body)) in chunk.iter().zip(bodies).enumerate() {
+                let event: SpriteHistoryEvent = match serde_json::from_slice(&body) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("Skipping unreadable sprite history event {}: {}", name, e);
+                        continue;
+                    }
+                };
+                if filter.digest.as_ref().is_some_and(|d| &event.digest != d)
+                    || filter
+                        .job_id
+                        .as_ref()
+                        .is_some_and(|j| event.job_id.as_ref() != Some(j))
+                {
+                    continue;
+                }
+                events.push(event);
+                if events.len() >= filter.limit {
+                    let read = chunk_index * HISTORY_FETCH_CONCURRENCY + i + 1;
+                    return Ok(SpriteHistoryPage {
+                        events,
+                        next_cursor: (read < names.len() || more_listed).then(|| name.clone()),
+                    });
+                }
+            }
+        }
+        if !more_listed {
             break;
         }
     }
+    Ok(SpriteHistoryPage {
+        events,
+        next_cursor: None,
+    })
+}
 
-    if !found {
-        index.sprites.push(SpriteIndexRecord {
-            sprite_name: sprite_name.to_string(),
-            rpc_url: rpc_url.to_string(),
-            do_poll_url,
-            status: "active".to_string(),
-            digest: digest.to_string(),
-            last_changed_at: now.clone(),
-        });
-    }
-    index.updated_at = now;
+/// This chain's sprite index (empty if none has been written yet). An index that can't
+/// be parsed is an error (see [`load_sprite_index`]).
+pub async fn sprite_index() -> Result<ActiveSpritesIndex, Box<dyn std::error::Error + Send + Sync>>
+{
+    let bucket = sprite_index_bucket()?;
+    let chain_id = sprite_chain_id();
+    let key = sprite_index_key(&chain_id);
+    Ok(load_sprite_index(&bucket, &key, &chain_id).await?.index)
+}
 
-    let payload = serde_json::to_vec_pretty(&index)?;
-    let response = bucket.put_object(&key, &payload).await?;
-    if response.status_code() != 200 {
-        return Err(format!(
-            "failed writing sprite index {}, status {}",
-            key,
-            response.status_code()
-        )
-        .into());
+/// Set `status` (and `last_changed_at`) on the index records of `sprite_names`.
+///
+/// Returns the updated records; names without a record are skipped.
+pub async fn set_sprite_status(
+    sprite_names: &[String],
+    status: &str,
+    job_id: Option<&str>,
+) -> Result<Vec<SpriteIndexRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let updated = update_sprite_index(job_id, |index| {
+        let now = chrono::Utc::now().to_rfc3339();
+        let mut updated = Vec::new();
+        for record in &mut index.sprites {
+            if sprite_names.contains(&record.sprite_name) {
+                record.status = status.to_string();
+                record.last_changed_at = now.clone();
+                updated.push(record.clone());
+            }
+        }
+        (!updated.is_empty()).then_some(updated)
+    })
+    .await?
+    .unwrap_or_default();
+
+    if !updated.is_empty() {
+        info!(
+            "Updated sprite index s3://{}/{}: {} sprite(s) now {}",
+            sprite_index_bucket_name(),
+            sprite_index_key(&sprite_chain_id()),
+            updated.len(),
+            status
+        );
     }
+    Ok(updated)
+}
+
+/// The sprite index record for `sprite_name` on this chain, if there is one.
+pub async fn sprite_index_record(
+    sprite_name: &str,
+) -> Result<Option<SpriteIndexRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(sprite_index()
+        .await?
+        .sprites
+        .into_iter()
+        .find(|record| record.sprite_name == sprite_name))
+}
+
+/// Upsert a sprite with `status` in chain-scoped index:
+/// s3://{SPRITE_INDEX_BUCKET}/{SPRITE_INDEX_PREFIX}/{CHAIN_ID}/active_sprites.json
+pub async fn upsert_sprite(
+    sprite_name: &str,
+    rpc_url: &str,
+    digest: &str,
+    profile: &str,
+    status: &str,
+    job_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let do_poll_url = format!("{}/do_poll", rpc_url.trim_end_matches('/'));
+
+    update_sprite_index(Some(job_id), |index| {
+        let now = chrono::Utc::now().to_rfc3339();
+        let existing = index.sprites.iter_mut().find(|record| {
+            record.sprite_name == sprite_name
+                || record.rpc_url == rpc_url
+                || record.do_poll_url == do_poll_url
+        });
+        match existing {
+            Some(record) => {
+                record.sprite_name = sprite_name.to_string();
+                record.rpc_url = rpc_url.to_string();
+                record.do_poll_url = do_poll_url.clone();
+                record.status = status.to_string();
+                record.digest = digest.to_string();
+                record.profile = profile.to_string();
+                record.last_changed_at = now;
+            }
+            None => index.sprites.push(SpriteIndexRecord {
+                sprite_name: sprite_name.to_string(),
+                rpc_url: rpc_url.to_string(),
+                do_poll_url: do_poll_url.clone(),
+                status: status.to_string(),
+                digest: digest.to_string(),
+                profile: profile.to_string(),
+                last_changed_at: now,
+            }),
+        }
+        Some(())
+    })
+    .await?;
+
     info!(
-        "Updated sprite index s3://{}/{} with active sprite {}",
-        bucket.name(),
-        key,
+        "Updated sprite index s3://{}/{} with {} sprite {}",
+        sprite_index_bucket_name(),
+        sprite_index_key(&sprite_chain_id()),
+        status,
         sprite_name
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prod_exports_stay_at_the_digest() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        assert_eq!(export_prefix(&digest, "prod"), digest);
+        assert_eq!(
+            export_prefix(&digest, "staging"),
+            format!("staging/{}", digest)
+        );
+    }
+
+    #[test]
+    fn profile_prefixes_never_nest_in_a_digest_prefix() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let staging = export_prefix(&digest, "staging");
+        assert!(!staging.starts_with(&format!("{}/", export_prefix(&digest, "prod"))));
+        assert!(!format!("{}/", staging).starts_with(&format!("{}/", digest)));
+    }
+
+    fn record(name: &str, digest: &str, status: &str, at: &str) -> SpriteIndexRecord {
+        SpriteIndexRecord {
+            sprite_name: name.to_string(),
+            rpc_url: format!("https://{}.sprites.app", name),
+            do_poll_url: format!("https://{}.sprites.app/do_poll", name),
+            status: status.to_string(),
+            digest: digest.to_string(),
+            profile: "prod".to_string(),
+            last_changed_at: at.to_string(),
+        }
+    }
+
+    #[test]
+    fn history_covers_changed_and_new_records_only() {
+        let t0 = "2026-01-01T00:00:00+00:00";
+        let t1 = "2026-01-02T00:00:00+00:00";
+        let before = vec![
+            record("lane-a", "sha256:a", SPRITE_ACTIVE, t0),
+            record("lane-b", "sha256:b", SPRITE_ACTIVE, t0),
+        ];
+        let after = vec![
+            record("lane-a", "sha256:a", SPRITE_ACTIVE, t0),
+            record("lane-b", "sha256:b2", SPRITE_STOPPED, t1),
+            record("lane-c", "sha256:c", SPRITE_ACTIVE, t1),
+        ];
+
+        let events = history_events(&before, &after, Some("job-1"));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].sprite_name, "lane-b");
+        assert_eq!(events[0].previous_digest.as_deref(), Some("sha256:b"));
+        assert_eq!(events[0].old_status.as_deref(), Some(SPRITE_ACTIVE));
+        assert_eq!(events[0].new_status, SPRITE_STOPPED);
+        assert_eq!(events[0].at, t1);
+        assert_eq!(events[1].sprite_name, "lane-c");
+        assert_eq!(events[1].previous_digest, None);
+        assert_eq!(events[1].old_status, None);
+        assert!(events.iter().all(|e| e.job_id.as_deref() == Some("job-1")));
+    }
+
+    #[test]
+    fn parses_history_object_names() {
+        assert_eq!(
+            parse_history_name("98232345678901234-lane-0123456789ab-staging-1a2b3c4d.json"),
+            Some(("98232345678901234", "lane-0123456789ab-staging"))
+        );
+        assert_eq!(parse_history_name("98232345678901234.json"), None);
+        assert_eq!(
+            parse_history_name("20260102T030405.000000Z-lane-0123456789ab-1a2b3c4d.json"),
+            None
+        );
+        assert_eq!(parse_history_name("notes.txt"), None);
+    }
+
+    #[test]
+    fn history_keys_sort_newest_first() {
+        let older = DateTime::parse_from_rfc3339("2026-01-02T03:04:05Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let newer = older + chrono::Duration::microseconds(1);
+        let (older, newer) = (history_key_time(older), history_key_time(newer));
+        assert_eq!(older.len(), 17);
+        assert!(newer < older);
+    }
+}
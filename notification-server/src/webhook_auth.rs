@@ -0,0 +1,298 @@
+//! HMAC-SHA256 request signatures for `POST /notify` and the `/jobs` endpoints.
+//!
+//! A signed request carries three headers:
+//! - `x-lane-timestamp`: Unix time (seconds) at which it was signed
+//! - `x-lane-nonce`: a value the sender never reuses
+//! - `x-lane-signature`: `sha256=<hex>`, the HMAC-SHA256 of
+//!   `{METHOD}\n{path?query}\n{timestamp}.{nonce}.{raw body}`
+//!
+//! Covering the method and target means a signature for one endpoint can't be replayed
+//! against another (say a signed `GET /jobs` as `POST /jobs/{id}/cancel`, both empty-bodied).
+//!
+//! Any secret listed in `LANE_NOTIFY_HMAC_SECRETS` (comma-separated) is accepted, so a new
+//! secret can be rolled out to senders before the old one is removed. Requests signed more
+//! than `LANE_NOTIFY_SIGNATURE_TOLERANCE_SECS` (default 300) away from our clock are
+//! rejected, and a nonce is accepted once within that window. Seen nonces live in memory
+//! only; anything older than the window is rejected by the timestamp check anyway.
+
+use axum::http::{HeaderMap, Method, Uri};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use subtle::ConstantTimeEq;
+
+pub const SIGNATURE_HEADER: &str = "x-lane-signature";
+pub const TIMESTAMP_HEADER: &str = "x-lane-timestamp";
+pub const NONCE_HEADER: &str = "x-lane-nonce";
+
+const MAX_NONCE_LEN: usize = 128;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a signed request was rejected.
+#[derive(Debug)]
+pub enum SignatureError {
+    MissingHeader(&'static str),
+    BadTimestamp,
+    StaleTimestamp,
+    BadNonce,
+    BadSignature,
+    ReplayedNonce,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::MissingHeader(name) => write!(f, "missing {} header", name),
+            SignatureError::BadTimestamp => {
+                write!(f, "{} is not a Unix timestamp", TIMESTAMP_HEADER)
+            }
+            SignatureError::StaleTimestamp => {
+                write!(f, "{} is outside the allowed window", TIMESTAMP_HEADER)
+            }
+            SignatureError::BadNonce => write!(f, "{} is too long", NONCE_HEADER),
+            SignatureError::BadSignature => write!(f, "signature does not match"),
+            SignatureError::ReplayedNonce => write!(f, "nonce was already used"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Active signing secrets from `LANE_NOTIFY_HMAC_SECRETS`; empty when signing is off.
+pub fn secrets() -> Vec<String> {
+    std::env::var("LANE_NOTIFY_HMAC_SECRETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn tolerance_secs() -> i64 {
+    std::env::var("LANE_NOTIFY_SIGNATURE_TOLERANCE_SECS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(300)
+}
+
+/// Compare two shared secrets without leaking where they differ.
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, SignatureError> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .ok_or(SignatureError::MissingHeader(name))
+}
+
+/// The MAC of a request, ready to be finalized or verified.
+fn request_mac(
+    secret: &str,
+    method: &Method,
+    uri: &Uri,
+    timestamp: &str,
+    nonce: &str,
+    body: &[u8],
+) -> Option<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+    let target = uri.path_and_query().map_or(uri.path(), |pq| pq.as_str());
+    mac.update(method.as_str().as_bytes());
+    mac.update(b"\n");
+    mac.update(target.as_bytes());
+    mac.update(b"\n");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(nonce.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Some(mac)
+}
+
+/// Check a signed request against every active secret, then burn its nonce.
+pub fn verify(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &[u8],
+    secrets: &[String],
+) -> Result<(), SignatureError> {
+    let timestamp_raw = header(headers, TIMESTAMP_HEADER)?;
+    let nonce = header(headers, NONCE_HEADER)?;
+    let signature_raw = header(headers, SIGNATURE_HEADER)?;
+
+    let timestamp: i64 = timestamp_raw
+        .parse()
+        .map_err(|_| SignatureError::BadTimestamp)?;
+    let now = chrono::Utc::now().timestamp();
+    let tolerance = tolerance_secs();
+    if (now - timestamp).abs() > tolerance {
+        return Err(SignatureError::StaleTimestamp);
+    }
+    if nonce.len() > MAX_NONCE_LEN {
+        return Err(SignatureError::BadNonce);
+    }
+
+    let signature_hex = signature_raw
+        .strip_prefix("sha256=")
+        .unwrap_or(signature_raw);
+    let signature = hex::decode(signature_hex).map_err(|_| SignatureError::BadSignature)?;
+
+    let matched = secrets.iter().any(|secret| {
+        request_mac(secret, method, uri, timestamp_raw, nonce, body)
+            // verify_slice compares in constant time.
+            .is_some_and(|mac| mac.verify_slice(&signature).is_ok())
+    });
+    if !matched {
+        return Err(SignatureError::BadSignature);
+    }
+
+    // Only authentic requests reach the nonce cache, so it can't be flooded.
+    remember_nonce(nonce, timestamp, now - tolerance)
+}
+
+fn remember_nonce(nonce: &str, timestamp: i64, oldest_valid: i64) -> Result<(), SignatureError> {
+    static SEEN: OnceLock<Mutex<HashMap<String, i64>>> = OnceLock::new();
+    let mut seen = SEEN
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    seen.retain(|_, ts| *ts >= oldest_valid);
+    if seen.contains_key(nonce) {
+        return Err(SignatureError::ReplayedNonce);
+    }
+    seen.insert(nonce.to_string(), timestamp);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    fn signed(
+        secret: &str,
+        method: &Method,
+        uri: &Uri,
+        timestamp: i64,
+        nonce: &str,
+        body: &[u8],
+    ) -> HeaderMap {
+        let timestamp = timestamp.to_string();
+        let mac = request_mac(secret, method, uri, &timestamp, nonce, body).unwrap();
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, timestamp.parse().unwrap());
+        headers.insert(NONCE_HEADER, nonce.parse().unwrap());
+        headers.insert(SIGNATURE_HEADER, signature.parse().unwrap());
+        headers
+    }
+
+    fn fresh_nonce() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    fn secrets(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_any_active_secret() {
+        let uri: Uri = "/notify".parse().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let headers = signed(SECRET, &Method::POST, &uri, now, &fresh_nonce(), b"{}");
+        assert!(verify(
+            &Method::POST,
+            &uri,
+            &headers,
+            b"{}",
+            &secrets(&["old-secret", SECRET])
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_secret() {
+        let uri: Uri = "/notify".parse().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let headers = signed("other", &Method::POST, &uri, now, &fresh_nonce(), b"{}");
+        assert!(matches!(
+            verify(&Method::POST, &uri, &headers, b"{}", &secrets(&[SECRET])),
+            Err(SignatureError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_method_or_target() {
+        let uri: Uri = "/jobs?limit=5".parse().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let headers = signed(SECRET, &Method::GET, &uri, now, &fresh_nonce(), b"");
+        let cancel: Uri = "/jobs/abc/cancel".parse().unwrap();
+        assert!(matches!(
+            verify(&Method::POST, &cancel, &headers, b"", &secrets(&[SECRET])),
+            Err(SignatureError::BadSignature)
+        ));
+        let other_query: Uri = "/jobs?limit=500".parse().unwrap();
+        assert!(matches!(
+            verify(
+                &Method::GET,
+                &other_query,
+                &headers,
+                b"",
+                &secrets(&[SECRET])
+            ),
+            Err(SignatureError::BadSignature)
+        ));
+        assert!(matches!(
+            verify(&Method::POST, &uri, &headers, b"", &secrets(&[SECRET])),
+            Err(SignatureError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_clock_skew() {
+        let uri: Uri = "/notify".parse().unwrap();
+        let stale = chrono::Utc::now().timestamp() - tolerance_secs() - 60;
+        let headers = signed(SECRET, &Method::POST, &uri, stale, &fresh_nonce(), b"{}");
+        assert!(matches!(
+            verify(&Method::POST, &uri, &headers, b"{}", &secrets(&[SECRET])),
+            Err(SignatureError::StaleTimestamp)
+        ));
+    }
+
+    #[test]
+    fn rejects_replayed_nonce() {
+        let uri: Uri = "/notify".parse().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let headers = signed(SECRET, &Method::POST, &uri, now, &fresh_nonce(), b"{}");
+        let secrets = secrets(&[SECRET]);
+        assert!(verify(&Method::POST, &uri, &headers, b"{}", &secrets).is_ok());
+        assert!(matches!(
+            verify(&Method::POST, &uri, &headers, b"{}", &secrets),
+            Err(SignatureError::ReplayedNonce)
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_headers() {
+        let uri: Uri = "/notify".parse().unwrap();
+        assert!(matches!(
+            verify(
+                &Method::POST,
+                &uri,
+                &HeaderMap::new(),
+                b"{}",
+                &secrets(&[SECRET])
+            ),
+            Err(SignatureError::MissingHeader(TIMESTAMP_HEADER))
+        ));
+    }
+}
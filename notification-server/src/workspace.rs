@@ -0,0 +1,102 @@
+//! Per-job scratch space, so concurrent jobs never share export output or lane cache.
+//!
+//! Each job gets `LANE_WORK_DIR/<job id>/` (default `/data/lane-jobs`) containing:
+//! - `export/` — `lane export` output, uploaded to Tigris from there
+//! - `cache/` — the job's `XDG_CACHE_HOME`
+//! - `home/` — the job's `HOME`; `.cache` points at `cache/` (lane export looks for
+//!   `~/.cache/lane`) and `.docker` at the shared registry login in `LANE_HOME`
+//! - `tmp/` — the job's `TMPDIR`
+//! - `upload-state/` — progress of multipart uploads, so an upload resumed after a restart
+//!   skips the parts already stored
+//!
+//! The directory lives until the job's pipeline task ends, whatever the outcome.
+
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const DEFAULT_WORK_DIR: &str = "/data/lane-jobs";
+const DEFAULT_LANE_HOME: &str = "/data/lane-home";
+
+fn work_dir() -> PathBuf {
+    std::env::var("LANE_WORK_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_WORK_DIR))
+}
+
+#[derive(Debug, Clone)]
+pub struct JobWorkspace {
+    root: PathBuf,
+}
+
+impl JobWorkspace {
+    pub fn for_job(job_id: &str) -> Self {
+        Self {
+            root: work_dir().join(job_id),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn export_dir(&self) -> PathBuf {
+        self.root.join("export")
+    }
+
+    pub fn home_dir(&self) -> PathBuf {
+        self.root.join("home")
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        self.root.join("cache")
+    }
+
+    pub fn tmp_dir(&self) -> PathBuf {
+        self.root.join("tmp")
+    }
+
+    pub fn upload_state_dir(&self) -> PathBuf {
+        self.root.join("upload-state")
+    }
+
+    /// Create the directory layout. Safe to call on a workspace that already exists.
+    pub async fn prepare(&self) -> std::io::Result<()> {
+        let home = self.home_dir();
+        tokio::fs::create_dir_all(&home).await?;
+        tokio::fs::create_dir_all(self.cache_dir()).await?;
+        tokio::fs::create_dir_all(self.tmp_dir()).await?;
+
+        let lane_home = std::env::var("LANE_HOME").unwrap_or_else(|_| DEFAULT_LANE_HOME.into());
+        symlink_if_missing(&self.cache_dir(), &home.join(".cache")).await?;
+        symlink_if_missing(
+            &Path::new(&lane_home).join(".docker"),
+            &home.join(".docker"),
+        )
+        .await?;
+
+        info!("📁 Job workspace ready at {}", self.root.display());
+        Ok(())
+    }
+
+    /// Delete the workspace and everything in it.
+    pub async fn remove(&self) {
+        if tokio::fs::metadata(&self.root).await.is_err() {
+            return;
+        }
+        info!("🧹 Removing job workspace {}...", self.root.display());
+        if let Err(e) = tokio::fs::remove_dir_all(&self.root).await {
+            warn!(
+                "⚠️ Failed to remove job workspace {}: {}",
+                self.root.display(),
+                e
+            );
+        }
+    }
+}
+
+async fn symlink_if_missing(target: &Path, link: &Path) -> std::io::Result<()> {
+    if tokio::fs::symlink_metadata(link).await.is_ok() {
+        return Ok(());
+    }
+    tokio::fs::symlink(target, link).await
+}
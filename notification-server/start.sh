@@ -52,7 +52,7 @@ dockerd --debug --host=unix:///var/run/docker.sock --host=tcp://0.0.0.0:2376 --d
   echo "Docker is ready (background)"
 
   echo "Logging into registry (must complete before lane build can pull image)..."
-  if echo "$REGISTRY_PASSWORD" | docker login cli-backend-registry.fly.dev -u lane-container --password-stdin 2>&1; then
+  if echo "$REGISTRY_PASSWORD" | docker login "${LANE_REGISTRY_BASE:-cli-backend-registry.fly.dev}" -u "${REGISTRY_USERNAME:-lane-container}" --password-stdin 2>&1; then
     echo "REGISTRY_LOGIN_SUCCEEDED"
     touch /tmp/registry-login-done 2>/dev/null || true
   else